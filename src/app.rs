@@ -1,6 +1,9 @@
 use clap::{arg, command, value_parser, Arg, ArgAction, ArgMatches, ColorChoice, Command};
 use std::path::PathBuf;
 
+use crate::files::ConflictStyle;
+use crate::section::MarkerStyle;
+
 pub fn get_vec_args<'a>(matches: &'a ArgMatches, name: &str) -> Vec<&'a str> {
     let sections = matches
         .get_many::<String>(name)
@@ -10,6 +13,26 @@ pub fn get_vec_args<'a>(matches: &'a ArgMatches, name: &str) -> Vec<&'a str> {
     return sections;
 }
 
+// None keeps the style the file already uses
+pub fn get_marker_style(matches: &ArgMatches) -> Option<MarkerStyle> {
+    match matches.get_one::<String>("marker-style")?.as_str() {
+        "compact" => Some(MarkerStyle::Compact),
+        _ => Some(MarkerStyle::Expanded),
+    }
+}
+
+pub fn is_dry_run(matches: &ArgMatches) -> bool {
+    *matches.get_one::<bool>("dry-run").unwrap()
+}
+
+pub fn get_conflict_style(matches: &ArgMatches) -> ConflictStyle {
+    if *matches.get_one::<bool>("conflict-markers").unwrap() {
+        ConflictStyle::Markers
+    } else {
+        ConflictStyle::Report
+    }
+}
+
 pub fn build_app() -> Command {
     command!()
         .color(ColorChoice::Always)
@@ -17,6 +40,12 @@ pub fn build_app() -> Command {
         .arg_required_else_help(true)
         .about("instant manager of sections in dotfiles")
         .author("paperbenni <paperbenni@gmail.com>")
+        .arg(
+            arg!(--"dry-run" "print changes as unified diffs instead of writing them, exit with 2 if there are any")
+                .required(false)
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
         .subcommand(
             Command::new("test")
                 .about("testing stuff")
@@ -35,13 +64,18 @@ pub fn build_app() -> Command {
                     arg!(-m --metafile "use meta file")
                         .required(false)
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"marker-style" <STYLE> "write markers on separate lines or combined into the begin line, defaults to the style of the file")
+                        .required(false)
+                        .value_parser(["expanded", "compact"]),
                 ),
         )
         .subcommand(
             Command::new("update")
                 .about("update sections from sources")
                 .arg(
-                    arg!(-f --file "file to update")
+                    arg!(-f --file <FILE> "file to update")
                         .required(true)
                         .value_parser(value_parser!(PathBuf)),
                 )
@@ -51,21 +85,41 @@ pub fn build_app() -> Command {
                         .action(ArgAction::SetTrue),
                 )
                 .arg(
-                    arg!(-s --section "only update section, default is all")
+                    arg!(-s --section <SECTION> "only update section, default is all")
                         .required(false)
                         .action(ArgAction::Append),
+                )
+                .arg(
+                    arg!(--offline "only use cached copies of remote sources")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--trust "run commands of command sources without asking")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"accept-new" "accept content not matching its sha256 pin and update the pin")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"conflict-markers" "write merge conflicts with markers instead of skipping")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
             Command::new("query")
                 .about("print section from file")
                 .arg(
-                    arg!(--file "file to search through")
+                    arg!(--file <FILE> "file to search through")
                         .required(true)
                         .value_parser(value_parser!(PathBuf)),
                 )
                 .arg(
-                    arg!(--section "section to print")
+                    arg!(--section <SECTION> "section to print")
                         .required(true)
                         .action(ArgAction::Append)
                         .value_parser(value_parser!(String)),
@@ -89,6 +143,16 @@ pub fn build_app() -> Command {
                         .help("file or directory to apply")
                         .required(true)
                         .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"conflict-markers" "write merge conflicts with markers instead of skipping")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--"all-or-nothing" "check every target before writing and restore all of them if one fails")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -106,18 +170,159 @@ pub fn build_app() -> Command {
                         .action(ArgAction::SetTrue),
                 )
                 .arg(
-                    arg!(-s --section "section to delete")
+                    arg!(-s --section <SECTION> "section to delete")
                         .required(true)
                         .action(ArgAction::Append)
                         .value_parser(value_parser!(String))
                         .action(ArgAction::Append),
                 ),
         )
+        .subcommand(
+            Command::new("lint")
+                .about("report problems with imosid markers")
+                .arg(
+                    Arg::new("path")
+                        .help("file or directory to lint")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-f --format <FORMAT> "output format")
+                        .required(false)
+                        .value_parser(["human", "json", "quickfix"])
+                        .default_value("human"),
+                )
+                .arg(
+                    arg!(--"deny-warnings" "also exit with an error if there are warnings")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("outdated")
+                .about("compare sections with their sources, without changing anything")
+                .after_help(
+                    "exits with 1 if updates are available and 2 if a source could not be checked",
+                )
+                .arg(
+                    Arg::new("path")
+                        .help("file or directory to check")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-f --format <FORMAT> "output format")
+                        .required(false)
+                        .value_parser(["table", "json"])
+                        .default_value("table"),
+                )
+                .arg(
+                    arg!(--offline "only use cached copies of remote sources")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("fmt")
+                .about("rewrite marker comments in their canonical form")
+                .arg(
+                    Arg::new("path")
+                        .help("file or directory to format")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--check "only report files that need formatting")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("show")
+                .about("print stored content with the given hash")
+                .arg(
+                    Arg::new("hash")
+                        .required(true)
+                        .help("hash of a section or a file managed by a metafile"),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("show changes to a file")
+                .arg(
+                    arg!(--original <FILE> "compare modified sections with the content they were compiled with")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("remove stored contents no file refers to")
+                .after_help(
+                    "contents of every file imosid has written are kept, nothing is removed if one of them cannot be read",
+                )
+                .arg(
+                    Arg::new("path")
+                        .help("also keep the contents of these files or directories")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-p --print "only print unreferenced contents, do not remove them")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("backups").about("list the backups taken before files were changed"),
+        )
+        .subcommand(
+            Command::new("rollback")
+                .about("restore files from a backup")
+                .after_help(
+                    "without --to the newest backup is undone, the rollback is backed up as well",
+                )
+                .arg(
+                    arg!(--to <ID> "undo this backup and all newer ones")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("path")
+                        .help("only restore these files or files in these directories")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("log")
+                .about("show the changes imosid made to files")
+                .after_help(
+                    "--since takes a date like 2024-05-01, a time like \"2024-05-01 12:30\" or an age like 3d, times are utc",
+                )
+                .arg(
+                    arg!(--file <FILE> "only changes to or from this file")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--section <SECTION> "only changes touching this section")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                )
+                .arg(
+                    arg!(--since <TIME> "only changes made since then")
+                        .required(false)
+                        .value_parser(value_parser!(String)),
+                ),
+        )
         .subcommand(
             Command::new("check")
                 .about("check directory for modified files")
                 .arg(
-                    arg!(--directory "directory to check")
+                    arg!(--directory <DIRECTORY> "directory to check")
                         .required(true)
                         .value_parser(value_parser!(PathBuf)),
                 ),
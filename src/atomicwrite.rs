@@ -0,0 +1,123 @@
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{fchown, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use xattr::FileExt;
+
+// same limit as the kernel has for resolving paths
+const MAX_SYMLINKS: usize = 40;
+
+/// replace the content of a file so it is never seen half written
+///
+/// the content goes to a temporary file next to the target, which is synced
+/// and renamed over it. symlinks are followed and the file they point to is
+/// replaced. mode, owner and extended attributes of an existing file are
+/// kept, unless a mode is given.
+pub fn write_atomic(path: &Path, content: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let path = resolve_symlinks(path)?;
+    let existing = fs::metadata(&path).ok();
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let Some(name) = path.file_name() else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a file", path.display()),
+        ));
+    };
+
+    let mode = mode.or(existing.as_ref().map(|existing| existing.mode() & 0o7777));
+
+    // a temporary file that gets a mode is only accessible by its owner
+    // until it has the owner and mode of the target, content comes after
+    // that. new files without a mode are created like any other file
+    let (tmppath, mut file) =
+        create_temporary(dir, name, if mode.is_some() { 0o600 } else { 0o666 })?;
+    let result = (|| {
+        if let Some(existing) = &existing {
+            // only root may give files away, everyone else keeps their own
+            match fchown(&file, Some(existing.uid()), Some(existing.gid())) {
+                Err(e) if e.kind() == ErrorKind::PermissionDenied => {}
+                result => result?,
+            }
+            copy_xattrs(&path, &file);
+        }
+        // chown clears setuid bits, the mode has to come after it
+        if let Some(mode) = mode {
+            file.set_permissions(Permissions::from_mode(mode))?;
+        }
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&tmppath, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmppath);
+        return result;
+    }
+
+    // make the rename itself survive a crash
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// the file a chain of symlinks ends at, which may not exist yet
+fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+    let mut path = path.to_path_buf();
+    for _ in 0..MAX_SYMLINKS {
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = fs::read_link(&path)?;
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            _ => return Ok(path),
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("too many levels of symlinks at {}", path.display()),
+    ))
+}
+
+// a new file in the same directory, so renaming it is atomic
+fn create_temporary(dir: &Path, name: &OsStr, mode: u32) -> io::Result<(PathBuf, File)> {
+    let mut attempt = 0;
+    loop {
+        let tmppath = dir.join(format!(
+            ".{}.imosid-{}-{}",
+            name.to_string_lossy(),
+            std::process::id(),
+            attempt
+        ));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&tmppath)
+        {
+            Ok(file) => return Ok((tmppath, file)),
+            // left behind by a crash
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// attributes the user may not set, like security labels, are left out
+fn copy_xattrs(from: &Path, to: &File) {
+    let Ok(names) = xattr::list(from) else {
+        return;
+    };
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(from, &name) {
+            let _ = to.set_xattr(&name, &value);
+        }
+    }
+}
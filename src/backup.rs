@@ -0,0 +1,360 @@
+use std::fs::{self, read_to_string};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use colored::Colorize;
+use toml::{Table, Value};
+
+use crate::atomicwrite::write_atomic;
+use crate::journal::{Journal, JournalEntry};
+use crate::{timestamp, xdg};
+
+const DEFAULT_KEEP: usize = 20;
+
+/// how many backup sets to keep, read from backups.toml
+///
+/// ```toml
+/// keep = 20    # number of backup sets
+/// max_age = 30 # days, older sets are removed even if there are fewer
+/// ```
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub keep: usize,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            keep: DEFAULT_KEEP,
+            max_age: None,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// policy from the user's backups.toml
+    /// errors in the file are reported and the default is used
+    pub fn load() -> RetentionPolicy {
+        let path = xdg::config_home().join("imosid/backups.toml");
+        if !path.is_file() {
+            return RetentionPolicy::default();
+        }
+        let policy = read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| RetentionPolicy::from_toml(&content));
+        match policy {
+            Ok(policy) => policy,
+            Err(e) => {
+                eprintln!("{} {}: {}", "invalid".red(), path.display(), e);
+                RetentionPolicy::default()
+            }
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<RetentionPolicy, String> {
+        let value = content.parse::<Table>().map_err(|e| e.to_string())?;
+        let number = |key: &str| match value.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(number)) if *number > 0 => Ok(Some(*number as u64)),
+            Some(_) => Err(format!("{} has to be a positive number", key)),
+        };
+        Ok(RetentionPolicy {
+            keep: number("keep")?.map_or(DEFAULT_KEEP, |keep| keep as usize),
+            max_age: number("max_age")?.map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        })
+    }
+}
+
+/// a file as it was before imosid changed it
+#[derive(Clone, Debug)]
+pub struct BackupEntry {
+    pub path: PathBuf,
+    copy: Option<String>, // name of the copy in the set, None if the file did not exist
+    mode: Option<u32>,
+}
+
+impl BackupEntry {
+    /// false if the file was created by imosid
+    pub fn existed(&self) -> bool {
+        self.copy.is_some()
+    }
+}
+
+/// the files one run of imosid changed, as they were before
+pub struct BackupSet {
+    pub id: String,
+    pub created: u64, // seconds since the unix epoch
+    pub command: String,
+    pub files: Vec<BackupEntry>,
+    dir: PathBuf,
+}
+
+impl BackupSet {
+    fn load(dir: PathBuf) -> Option<BackupSet> {
+        let manifest = read_to_string(dir.join("manifest.toml"))
+            .ok()?
+            .parse::<Table>()
+            .ok()?;
+        let files = match manifest.get("files") {
+            Some(Value::Array(files)) => files
+                .iter()
+                .filter_map(|file| {
+                    Some(BackupEntry {
+                        path: PathBuf::from(file.get("path")?.as_str()?),
+                        copy: file.get("copy").and_then(Value::as_str).map(String::from),
+                        mode: file
+                            .get("mode")
+                            .and_then(Value::as_integer)
+                            .map(|mode| mode as u32),
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(BackupSet {
+            id: dir.file_name()?.to_string_lossy().to_string(),
+            created: manifest.get("created")?.as_integer()? as u64,
+            command: manifest
+                .get("command")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            files,
+            dir,
+        })
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        let mut manifest = Table::new();
+        manifest.insert(String::from("created"), Value::Integer(self.created as i64));
+        manifest.insert(String::from("command"), Value::String(self.command.clone()));
+        let files = self
+            .files
+            .iter()
+            .map(|entry| {
+                let mut file = Table::new();
+                file.insert(
+                    String::from("path"),
+                    Value::String(entry.path.to_string_lossy().to_string()),
+                );
+                if let Some(copy) = &entry.copy {
+                    file.insert(String::from("copy"), Value::String(copy.clone()));
+                }
+                if let Some(mode) = entry.mode {
+                    file.insert(String::from("mode"), Value::Integer(mode.into()));
+                }
+                Value::Table(file)
+            })
+            .collect();
+        manifest.insert(String::from("files"), Value::Array(files));
+        write_atomic(
+            &self.dir.join("manifest.toml"),
+            manifest.to_string().as_bytes(),
+            None,
+        )
+    }
+
+    /// copy a file into the set, unless it already is in it
+    pub fn add(&mut self, path: &Path) -> io::Result<()> {
+        let path = std::path::absolute(path)?;
+        if self.files.iter().any(|entry| entry.path == path) {
+            return Ok(());
+        }
+        let entry = match fs::metadata(&path) {
+            Ok(metadata) => {
+                let copy = self.files.len().to_string();
+                fs::copy(&path, self.dir.join(&copy))?;
+                BackupEntry {
+                    path,
+                    copy: Some(copy),
+                    mode: Some(metadata.permissions().mode() & 0o7777),
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BackupEntry {
+                path,
+                copy: None,
+                mode: None,
+            },
+            Err(e) => return Err(e),
+        };
+        self.files.push(entry);
+        self.write_manifest()
+    }
+
+    fn content(&self, entry: &BackupEntry) -> io::Result<Option<Vec<u8>>> {
+        match &entry.copy {
+            Some(copy) => Ok(Some(fs::read(self.dir.join(copy))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// timestamped backup sets, one for every run of imosid that changed files
+pub struct Backups {
+    dir: PathBuf,
+}
+
+impl Backups {
+    pub fn at(dir: PathBuf) -> Backups {
+        Backups { dir }
+    }
+
+    /// all backup sets, oldest first
+    pub fn list(&self) -> io::Result<Vec<BackupSet>> {
+        if !self.dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut sets = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            if let Some(set) = BackupSet::load(entry?.path()) {
+                sets.push(set);
+            }
+        }
+        // ids of sets taken in the same second only differ in their suffix
+        sets.sort_by_key(|set| (set.created, set.id.len(), set.id.clone()));
+        Ok(sets)
+    }
+
+    /// start a new, empty backup set
+    pub fn create(&self, command: &str) -> io::Result<BackupSet> {
+        let created = timestamp::now();
+        fs::create_dir_all(&self.dir)?;
+        let timestamp = timestamp::format_compact(created);
+        let mut attempt = 0;
+        let dir = loop {
+            let id = match attempt {
+                0 => timestamp.clone(),
+                _ => format!("{}-{}", timestamp, attempt),
+            };
+            let dir = self.dir.join(id);
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let set = BackupSet {
+            id: dir.file_name().unwrap().to_string_lossy().to_string(),
+            created,
+            command: String::from(command),
+            files: Vec::new(),
+            dir,
+        };
+        set.write_manifest()?;
+        Ok(set)
+    }
+
+    /// remove the sets the policy does not keep, return their ids
+    /// the newest set is always kept
+    pub fn prune(&self, policy: &RetentionPolicy) -> io::Result<Vec<String>> {
+        let sets = self.list()?;
+        let now = timestamp::now();
+        let mut removed = Vec::new();
+        for (index, set) in sets.iter().enumerate() {
+            let newer = sets.len() - index - 1;
+            let expired = policy
+                .max_age
+                .is_some_and(|max_age| now.saturating_sub(set.created) > max_age.as_secs());
+            if newer > 0 && (newer >= policy.keep || expired) {
+                fs::remove_dir_all(&set.dir)?;
+                removed.push(set.id.clone());
+            }
+        }
+        Ok(removed)
+    }
+
+    /// restore files as they were before the set with the given id,
+    /// and every set after it, was taken. without an id only the newest
+    /// set is undone. only files in or below paths are restored if any
+    /// are given. the current files are backed up first, so a rollback
+    /// can be rolled back as well, and every restored file is journaled.
+    /// a dry run only returns the files
+    pub fn rollback(
+        &self,
+        to: Option<&str>,
+        paths: &[PathBuf],
+        dry_run: bool,
+        journal: &Journal,
+    ) -> Result<Vec<PathBuf>, String> {
+        let sets = self.list().map_err(|e| e.to_string())?;
+        let first = match to {
+            Some(id) => sets
+                .iter()
+                .position(|set| set.id == id)
+                .ok_or_else(|| format!("there is no backup {}", id))?,
+            None if sets.is_empty() => return Err(String::from("there are no backups")),
+            None => sets.len() - 1,
+        };
+        let paths = paths
+            .iter()
+            .map(std::path::absolute)
+            .collect::<io::Result<Vec<PathBuf>>>()
+            .map_err(|e| e.to_string())?;
+
+        // the oldest copy of every file is the state before all of the sets
+        // everything is read before writing, the new backup set could prune them
+        let mut restore: Vec<(BackupEntry, Option<Vec<u8>>)> = Vec::new();
+        for set in &sets[first..] {
+            for entry in &set.files {
+                if restore
+                    .iter()
+                    .any(|(restored, _)| restored.path == entry.path)
+                    || !(paths.is_empty() || paths.iter().any(|path| entry.path.starts_with(path)))
+                {
+                    continue;
+                }
+                let content = set.content(entry).map_err(|e| {
+                    format!("could not read backup of {}: {}", entry.path.display(), e)
+                })?;
+                restore.push((entry.clone(), content));
+            }
+        }
+
+        if restore.is_empty() || dry_run {
+            return Ok(restore.into_iter().map(|(entry, _)| entry.path).collect());
+        }
+        let mut undo = self.create(&run_command()).map_err(|e| e.to_string())?;
+        let mut restored = Vec::new();
+        for (entry, content) in restore {
+            let record = JournalEntry::new(
+                &entry.path,
+                fs::read(&entry.path).ok().as_deref(),
+                content.as_deref(),
+            )
+            .with_source(&sets[first].dir);
+            let result = undo.add(&entry.path).and_then(|_| match content {
+                Some(content) => write_atomic(&entry.path, &content, entry.mode),
+                // imosid created the file
+                None => match fs::remove_file(&entry.path) {
+                    Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                    result => result,
+                },
+            });
+            if let Err(e) = result {
+                journal.record(&record.failed(&e.to_string()));
+                return Err(format!("could not restore {}: {}", entry.path.display(), e));
+            }
+            journal.record(&record);
+            restored.push(entry.path);
+        }
+        Ok(restored)
+    }
+}
+
+// how imosid was called, to tell backup sets apart
+pub fn run_command() -> String {
+    std::env::args()
+        .enumerate()
+        .map(|(index, arg)| match index {
+            // the program without the directory it was run from
+            0 => Path::new(&arg)
+                .file_name()
+                .map_or(arg.clone(), |name| name.to_string_lossy().to_string()),
+            _ => arg,
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
@@ -0,0 +1,77 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+use sha256::digest;
+use toml::{Table, Value};
+
+use crate::atomicwrite::write_atomic;
+
+/// local copy of a remote source, along with the validators
+/// needed to ask the server whether it is still current
+pub struct CachedResponse {
+    pub body: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// directory of cached responses
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn at(dir: PathBuf) -> Cache {
+        Cache { dir }
+    }
+
+    // entries are named after the hash of their url,
+    // the body and a toml file with the validators next to it
+    fn entry_path(&self, url: &str) -> PathBuf {
+        self.dir.join(digest(url))
+    }
+
+    pub fn load(&self, url: &str) -> Option<CachedResponse> {
+        let path = self.entry_path(url);
+        let body = fs::read_to_string(&path).ok()?;
+        let meta = fs::read_to_string(path.with_extension("toml"))
+            .ok()?
+            .parse::<Table>()
+            .ok()?;
+        let validator = |key: &str| meta.get(key).and_then(Value::as_str).map(String::from);
+        Some(CachedResponse {
+            body,
+            etag: validator("etag"),
+            last_modified: validator("last-modified"),
+        })
+    }
+
+    pub fn store(&self, url: &str, response: &CachedResponse) -> io::Result<()> {
+        let path = self.entry_path(url);
+        fs::create_dir_all(&self.dir)?;
+        let mut meta = Table::new();
+        meta.insert(String::from("url"), Value::String(String::from(url)));
+        if let Some(etag) = &response.etag {
+            meta.insert(String::from("etag"), Value::String(etag.clone()));
+        }
+        if let Some(last_modified) = &response.last_modified {
+            meta.insert(
+                String::from("last-modified"),
+                Value::String(last_modified.clone()),
+            );
+        }
+        // an entry without validators is not loaded, they are removed first
+        // and written last, so a body never ends up with the validators of
+        // another one
+        match fs::remove_file(path.with_extension("toml")) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        write_atomic(&path, response.body.as_bytes(), None)?;
+        write_atomic(
+            &path.with_extension("toml"),
+            meta.to_string().as_bytes(),
+            None,
+        )
+    }
+}
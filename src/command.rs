@@ -0,0 +1,171 @@
+use std::fs::read_to_string;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use colored::Colorize;
+use toml::Value;
+
+use crate::files::expand_tilde;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// which commands cmd: sources may run, read from commands.toml
+///
+/// ```toml
+/// allow = ["dircolors", "gsettings"]
+/// trusted = ["~/dotfiles"] # files in here may run commands without asking
+/// timeout = 10             # seconds, sources can override it with timeout=
+/// ```
+#[derive(Clone, Debug)]
+pub struct CommandPolicy {
+    pub allow: Vec<String>,
+    pub trusted: Vec<PathBuf>,
+    pub timeout: Duration,
+}
+
+impl Default for CommandPolicy {
+    /// nothing may run
+    fn default() -> Self {
+        CommandPolicy {
+            allow: Vec::new(),
+            trusted: Vec::new(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// what a finished command printed
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandPolicy {
+    /// policy from the user's commands.toml
+    /// errors in the file are reported and nothing is allowed
+    pub fn load() -> CommandPolicy {
+        let path = crate::xdg::config_home().join("imosid/commands.toml");
+        if !path.is_file() {
+            return CommandPolicy::default();
+        }
+        let content = match read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("could not read {}: {}", path.display(), e);
+                return CommandPolicy::default();
+            }
+        };
+        match CommandPolicy::from_toml(&content) {
+            Ok(policy) => policy,
+            Err(e) => {
+                eprintln!("{} {}: {}", "invalid".red(), path.display(), e);
+                CommandPolicy::default()
+            }
+        }
+    }
+
+    pub fn from_toml(content: &str) -> Result<CommandPolicy, String> {
+        let value = content.parse::<Value>().map_err(|e| e.to_string())?;
+        let strings = |key: &str| -> Result<Vec<String>, String> {
+            match value.get(key) {
+                None => Ok(Vec::new()),
+                Some(Value::Array(array)) => array
+                    .iter()
+                    .map(|item| {
+                        item.as_str()
+                            .map(String::from)
+                            .ok_or_else(|| format!("{} has to be a list of strings", key))
+                    })
+                    .collect(),
+                Some(_) => Err(format!("{} has to be a list of strings", key)),
+            }
+        };
+        let timeout = match value.get("timeout") {
+            None => DEFAULT_TIMEOUT,
+            Some(Value::Integer(seconds)) if *seconds > 0 => Duration::from_secs(*seconds as u64),
+            Some(_) => {
+                return Err(String::from(
+                    "timeout has to be a positive number of seconds",
+                ))
+            }
+        };
+        Ok(CommandPolicy {
+            allow: strings("allow")?,
+            trusted: strings("trusted")?
+                .iter()
+                .map(|dir| PathBuf::from(expand_tilde(dir)))
+                .collect(),
+            timeout,
+        })
+    }
+
+    /// programs have to be listed exactly as they are written in the source
+    pub fn allows(&self, program: &str) -> bool {
+        self.allow.iter().any(|allowed| allowed == program)
+    }
+
+    /// true if the file is inside one of the trusted directories
+    pub fn trusts(&self, file: &Path) -> bool {
+        let Ok(file) = file.canonicalize() else {
+            return false;
+        };
+        self.trusted.iter().any(|dir| {
+            dir.canonicalize()
+                .map(|dir| file.starts_with(dir))
+                .unwrap_or(false)
+        })
+    }
+}
+
+/// run a command without a shell, killing it once the timeout has passed
+pub fn run(command: &[String], timeout: Duration) -> Result<CommandOutput, String> {
+    let Some((program, args)) = command.split_first() else {
+        return Err(String::from("empty command"));
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("could not run {}: {}", program, e))?;
+
+    // read both pipes while waiting, a full pipe would block the command
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+    let status = wait_timeout(&mut child, timeout)?;
+    Ok(CommandOutput {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+fn read_pipe(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut bytes);
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    })
+}
+
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus, String> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("timed out after {} seconds", timeout.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
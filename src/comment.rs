@@ -1,4 +1,58 @@
+use crate::diagnostic::Diagnostic;
 use regex::Regex;
+use std::fmt;
+
+/// comment delimiters of a file format
+/// line comments only have a start, block comments also need an end
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommentSyntax {
+    pub start: String,
+    pub end: Option<String>,
+}
+
+impl CommentSyntax {
+    pub fn line(start: &str) -> CommentSyntax {
+        CommentSyntax {
+            start: String::from(start),
+            end: None,
+        }
+    }
+
+    pub fn block(start: &str, end: &str) -> CommentSyntax {
+        CommentSyntax {
+            start: String::from(start),
+            end: Some(String::from(end)),
+        }
+    }
+
+    /// wrap text into a comment, e.g. `/* text */`
+    pub fn wrap(&self, text: &str) -> String {
+        match &self.end {
+            Some(end) => format!("{}{} {}", self.start, text, end),
+            None => format!("{}{}", self.start, text),
+        }
+    }
+}
+
+/// parse syntax from its written form, `#` or `/* */`
+impl From<&str> for CommentSyntax {
+    fn from(syntax: &str) -> Self {
+        let mut parts = syntax.split_whitespace();
+        CommentSyntax {
+            start: String::from(parts.next().unwrap_or("")),
+            end: parts.next().map(String::from),
+        }
+    }
+}
+
+impl fmt::Display for CommentSyntax {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.end {
+            Some(end) => write!(f, "{} {}", self.start, end),
+            None => write!(f, "{}", self.start),
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 // give targetinfo sourceinfo, hashinfo and targetinfo required parameter fields
@@ -9,6 +63,7 @@ pub enum CommentType {
     TargetInfo,
     HashInfo,
     PermissionInfo,
+    SyntaxInfo,
 }
 
 impl CommentType {
@@ -19,155 +74,437 @@ impl CommentType {
             "hash" => CommentType::HashInfo,
             "source" => CommentType::SourceInfo,
             "permissions" => CommentType::PermissionInfo,
+            "syntax" => CommentType::SyntaxInfo,
             "target" => CommentType::TargetInfo,
             &_ => {
                 return Option::None;
             }
         })
     }
+
+    /// info comments carry an argument and can be combined into other markers
+    pub fn is_info(&self) -> bool {
+        !matches!(self, CommentType::SectionBegin | CommentType::SectionEnd)
+    }
 }
 
-impl Into<String> for CommentType {
-    fn into(self) -> String {
-        String::from(match self {
+impl From<CommentType> for String {
+    fn from(val: CommentType) -> Self {
+        String::from(match val {
             CommentType::SectionBegin => "begin",
             CommentType::SectionEnd => "end",
             CommentType::SourceInfo => "source",
             CommentType::TargetInfo => "target",
             CommentType::HashInfo => "hash",
             CommentType::PermissionInfo => "permissions",
+            CommentType::SyntaxInfo => "syntax",
         })
     }
 }
 
+/// parse an octal mode like 644 or 0755
+/// the digits are kept as written, 644 is stored as the number 644
+pub fn parse_permissions(argument: &str) -> Option<u32> {
+    if !(3..=4).contains(&argument.len()) || !argument.chars().all(|c| ('0'..='7').contains(&c)) {
+        return None;
+    }
+    argument.parse::<u32>().ok()
+}
+
+/// mode bits of permissions stored the way parse_permissions keeps them
+pub fn permission_mode(permissions: u32) -> Option<u32> {
+    u32::from_str_radix(&permissions.to_string(), 8).ok()
+}
+
+/// key=value arguments of a comment, in the order they were written
+pub type CommentOptions = Vec<(String, String)>;
+
+/// info comment to combine into a marker: type, argument and options
+pub type InfoComment<'a> = (CommentType, &'a str, &'a [(String, String)]);
+
 #[derive(Clone)]
 pub struct Specialcomment {
     pub line: u32,       // line number comment is at in file
     pub section: String, // section name extracted from prefix
     pub comment_type: CommentType,
     pub argument: Option<String>, // optional argument, used for hashes etc
+    pub options: CommentOptions,  // key=value arguments
+    pub indent: String,           // whitespace in front of the marker
 }
 
 impl Specialcomment {
     pub fn new_string(
-        commentsymbol: &str,
+        commentsyntax: &CommentSyntax,
         ctype: CommentType,
         section_name: &str,
         argument: Option<&str>,
+        options: &[(String, String)],
     ) -> String {
-        format!(
-            "{}... {} {}{}\n",
-            commentsymbol,
+        commentsyntax.wrap(&format!(
+            "... {} {}{}{}",
             section_name,
             Into::<String>::into(ctype),
-            if argument.is_some() {
-                format!(" {}", argument.unwrap())
+            if let Some(argument) = argument {
+                format!(" {}", quote(argument))
             } else {
                 String::from("")
-            }
-        )
+            },
+            format_options(options)
+        ))
     }
 
-    pub fn from_line(line: &str, commentsymbol: &str, linenumber: u32) -> Option<Specialcomment> {
-        if !line.starts_with(commentsymbol) {
-            return Option::None;
+    /// marker with info comments combined into it as key=value pairs
+    pub fn new_combined_string(
+        commentsyntax: &CommentSyntax,
+        ctype: CommentType,
+        section_name: &str,
+        infos: &[InfoComment],
+    ) -> String {
+        let mut text = format!("... {} {}", section_name, Into::<String>::into(ctype));
+        for (infotype, argument, options) in infos {
+            text.push_str(&format!(
+                " {}={}{}",
+                Into::<String>::into(infotype.clone()),
+                quote(argument),
+                format_options(options)
+            ));
         }
+        commentsyntax.wrap(&text)
+    }
+
+    /// parse all imosid comments on a line
+    /// a single line can combine several comments as key=value pairs,
+    /// for example `#... name begin hash=ABC source=../x.conf`
+    /// malformed markers result in a diagnostic explaining the problem
+    pub fn from_line(
+        line: &str,
+        commentsyntax: &CommentSyntax,
+        linenumber: u32,
+    ) -> Result<Vec<Specialcomment>, Box<Diagnostic>> {
+        // markers may be indented to sit inside nested blocks
+        let body = line.trim_start_matches([' ', '\t']);
+        if !body.starts_with(&commentsyntax.start) {
+            return Ok(Vec::new());
+        }
+        let indent = &line[..line.len() - body.len()];
 
         // construct regex that matches valid comments
-        let mut iscomment = String::from("^ *");
-        iscomment.push_str(&commentsymbol);
-        iscomment.push_str(" *\\.\\.\\. *(.*)");
+        let mut iscomment = String::from("^[ \t]*");
+        iscomment.push_str(&regex::escape(&commentsyntax.start));
+        iscomment.push_str(" *\\.\\.\\. *(.*?)");
+        // block comments have to be closed on the same line
+        if let Some(end) = &commentsyntax.end {
+            iscomment.push_str(" *");
+            iscomment.push_str(&regex::escape(end));
+        }
+        iscomment.push_str(" *$");
         let commentregex = Regex::new(&iscomment).unwrap();
 
-        let keywords = commentregex.captures(&line);
+        let Some(captures) = commentregex.captures(line) else {
+            return Ok(Vec::new());
+        };
+        let text = captures.get(1).unwrap();
+        let textcolumn = line[..text.start()].chars().count() as u32 + 1;
+        let tokens = tokenize(text.as_str())
+            .map_err(|e| Box::new(Diagnostic::error("invalid-quoting", linenumber, &e)))?;
+        let column = |token: &Token| textcolumn + token.column as u32;
 
-        if let Some(captures) = &keywords {
-            let keywords = captures
-                .get(1)
-                .unwrap()
-                .as_str()
-                .split(" ")
-                .collect::<Vec<&str>>();
+        // needs at least a section and a keyword
+        if tokens.len() < 2 || tokens[0].key.is_some() || tokens[1].key.is_some() {
+            return Err(Box::new(
+                Diagnostic::error(
+                    "incomplete-marker",
+                    linenumber,
+                    "marker needs a section name and a keyword",
+                )
+                .with_column(textcolumn),
+            ));
+        }
 
-            // needs at least a section and a keyword
-            if keywords.len() < 2 {
-                return Option::None;
-            }
+        let sectionname = tokens[0].value.as_str();
+        let keyword = tokens[1].value.as_str();
 
-            let sectionname = keywords[0];
-            let keyword = keywords[1];
-            //comment argument, example #...all source ARGUMENT
-            let cargument: Option<String> = if keywords.len() > 2 {
-                Option::Some(String::from(keywords[2]))
-            } else {
-                Option::None
-            };
-
-            let tmptype: CommentType;
-            tmptype = CommentType::from_keyword(keyword)?;
-            match tmptype {
-                CommentType::HashInfo => {
-                    if cargument == None {
-                        println!("missing hash value on line {}", linenumber);
-                        return Option::None;
-                    }
+        // split off key=value pairs, pairs named like comment types
+        // are combined comments, all others are options of the comment before them
+        let mut arguments = Vec::new();
+        let mut combined: Vec<(&Token, CommentOptions)> = Vec::new();
+        let mut options = Vec::new();
+        for token in &tokens[2..] {
+            match &token.key {
+                Some(key) if CommentType::from_keyword(key).is_some() => {
+                    combined.push((token, Vec::new()));
                 }
-                CommentType::SourceInfo => {
-                    if cargument.is_some() {
-                        println!("updating from source not implemented yet");
-                        unimplemented!();
-                        //TODO do something
-                        //fetch from file/url/git
-                    } else {
-                        println!("missing source file argument on line {}", linenumber);
-                        return Option::None;
+                Some(key) => match combined.last_mut() {
+                    Some((_, combined_options)) => {
+                        combined_options.push((key.clone(), token.value.clone()))
                     }
+                    None => options.push((key.clone(), token.value.clone())),
+                },
+                None if !combined.is_empty() => {
+                    return Err(Box::new(
+                        Diagnostic::error(
+                            "argument-after-option",
+                            linenumber,
+                            "positional argument after key=value",
+                        )
+                        .with_column(column(token)),
+                    ));
+                }
+                None => arguments.push(token),
+            }
+        }
+
+        //comment argument, example #...all source ARGUMENT
+        let cargument = if arguments.is_empty() {
+            Option::None
+        } else if keyword == "syntax" {
+            // block syntax consists of two delimiters
+            Option::Some(
+                arguments
+                    .iter()
+                    .map(|token| token.value.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            )
+        } else if arguments.len() == 1 {
+            Some(arguments[0].value.clone())
+        } else {
+            return Err(Box::new(
+                Diagnostic::error(
+                    "too-many-arguments",
+                    linenumber,
+                    "too many arguments, quote arguments containing spaces",
+                )
+                .with_column(column(arguments[1])),
+            ));
+        };
+
+        let mut comments = Vec::new();
+        comments.push(
+            Specialcomment::new(linenumber, sectionname, keyword, cargument, options)
+                .map_err(|e| Box::new(e.with_column(column(&tokens[1]))))?,
+        );
+        for (token, options) in combined {
+            let key = token.key.as_deref().unwrap_or_default();
+            let argument = Some(token.value.clone());
+            let comment = Specialcomment::new(linenumber, sectionname, key, argument, options)
+                .map_err(|e| Box::new(e.with_column(column(token))))?;
+            if !comment.comment_type.is_info() {
+                return Err(Box::new(
+                    Diagnostic::error(
+                        "invalid-combination",
+                        linenumber,
+                        &format!("{} cannot be combined into another marker", key),
+                    )
+                    .with_column(column(token)),
+                ));
+            }
+            comments.push(comment);
+        }
+        for comment in comments.iter_mut() {
+            comment.indent = String::from(indent);
+        }
+        Ok(comments)
+    }
+
+    /// create a comment from its parts
+    /// fails if the keyword is unknown or the argument is invalid
+    fn new(
+        linenumber: u32,
+        sectionname: &str,
+        keyword: &str,
+        cargument: Option<String>,
+        options: CommentOptions,
+    ) -> Result<Specialcomment, Box<Diagnostic>> {
+        let Some(tmptype) = CommentType::from_keyword(keyword) else {
+            return Err(Box::new(Diagnostic::warning(
+                "unknown-keyword",
+                linenumber,
+                &format!("unknown marker keyword {}", keyword),
+            )));
+        };
+        let missing = |what: &str| {
+            Err(Box::new(Diagnostic::error(
+                "missing-argument",
+                linenumber,
+                &format!("missing {} value", what),
+            )))
+        };
+        // some comments only make sense for the whole file
+        let file_only = || {
+            Err(Box::new(Diagnostic::error(
+                "file-only",
+                linenumber,
+                &format!(
+                    "{} can only apply to the whole file, use section all",
+                    keyword
+                ),
+            )))
+        };
+        match tmptype {
+            CommentType::HashInfo => {
+                if cargument.is_none() {
+                    return missing("hash");
                 }
-                CommentType::PermissionInfo => {
-                    // permissioms can only be set for the entire file
-                    if sectionname != "all" {
-                        return Option::None;
+            }
+            CommentType::SourceInfo => {
+                if cargument.is_none() {
+                    return missing("source");
+                }
+            }
+            CommentType::PermissionInfo => {
+                // permissioms can only be set for the entire file
+                if sectionname != "all" {
+                    return file_only();
+                }
+                match &cargument {
+                    None => {
+                        return missing("permissions");
                     }
-                    match &cargument {
-                        None => {
-                            return Option::None;
+                    Some(arg) => {
+                        if parse_permissions(arg).is_none() {
+                            return Err(Box::new(Diagnostic::error(
+                                "invalid-permissions",
+                                linenumber,
+                                &format!(
+                                    "invalid permissions {}, expected 3 or 4 octal digits like 644",
+                                    arg
+                                ),
+                            )));
+                        }
+                    }
+                }
+            }
+            CommentType::TargetInfo => {
+                if sectionname != "all" {
+                    return file_only();
+                }
+                if cargument.is_none() {
+                    return missing("target");
+                }
+            }
+            CommentType::SyntaxInfo => {
+                // the comment syntax is the same for the whole file
+                if sectionname != "all" {
+                    return file_only();
+                }
+                if cargument.is_none() {
+                    return missing("syntax");
+                }
+            }
+            CommentType::SectionBegin | CommentType::SectionEnd => {}
+        }
+
+        Ok(Specialcomment {
+            line: linenumber,
+            section: String::from(sectionname),
+            comment_type: tmptype,
+            argument: cargument,
+            options,
+            indent: String::new(),
+        })
+    }
+}
+
+/// a word of a marker comment, `value` or `key=value`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Token {
+    pub key: Option<String>,
+    pub value: String,
+    pub column: usize, // character offset of the word in the text
+}
+
+// keys of key=value arguments are plain words
+fn is_key(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// split marker text into words
+/// words are separated by any amount of whitespace,
+/// double quotes allow spaces and backslash escapes, single quotes are literal
+pub fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut column = 0;
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {
+            column += 1;
+        }
+        if chars.peek().is_none() {
+            return Ok(tokens);
+        }
+        let remaining = chars.clone().count();
+
+        let mut word = String::new();
+        let mut key = None;
+        // only a key written before any quotes counts
+        let mut quoted = false;
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '\\' => word.push(chars.next().ok_or("trailing backslash")?),
+                '"' => {
+                    quoted = true;
+                    loop {
+                        match chars.next().ok_or("unterminated double quote")? {
+                            '"' => break,
+                            '\\' => word.push(chars.next().ok_or("unterminated double quote")?),
+                            c => word.push(c),
                         }
-                        //todo: more validation. maybe own permission type?
-                        Some(arg) => match arg.parse::<u32>() {
-                            Err(_) => {
-                                return Option::None;
-                            }
-                            Ok(_) => {}
-                        },
                     }
                 }
-                CommentType::TargetInfo => {
-                    if sectionname == "all" {
-                        if cargument == None {
-                            println!("missing target value on line {}", linenumber);
-                            return Option::None;
+                '\'' => {
+                    quoted = true;
+                    loop {
+                        match chars.next().ok_or("unterminated single quote")? {
+                            '\'' => break,
+                            c => word.push(c),
                         }
-                    } else {
-                        println!(
-                            "warning: target can only apply to the whole file {}",
-                            linenumber
-                        );
-                        return Option::None;
                     }
                 }
-                _ => {
-                    println!("warning: incomplete imosid comment on {}", linenumber);
-                    return Option::None;
+                '=' if key.is_none() && !quoted && is_key(&word) => {
+                    key = Some(word);
+                    word = String::new();
                 }
+                c => word.push(c),
             }
+        }
+        tokens.push(Token {
+            key,
+            value: word,
+            column,
+        });
+        column += remaining - chars.clone().count();
+    }
+}
 
-            return Some(Specialcomment {
-                line: linenumber,
-                section: String::from(sectionname),
-                comment_type: tmptype,
-                argument: cargument,
-            });
-        };
-        return Option::None;
+// options written as key=value, each preceded by a space
+fn format_options(options: &[(String, String)]) -> String {
+    options
+        .iter()
+        .map(|(key, value)| format!(" {}={}", key, quote(value)))
+        .collect()
+}
+
+/// quote an argument if it would not survive tokenizing as is
+pub fn quote(argument: &str) -> String {
+    let needs_quotes = argument.is_empty()
+        || argument
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\')
+        || argument.split_once('=').is_some_and(|(key, _)| is_key(key));
+    if !needs_quotes {
+        return String::from(argument);
+    }
+    let mut quoted = String::from("\"");
+    for c in argument.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
     }
+    quoted.push('"');
+    quoted
 }
@@ -1,6 +1,7 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 use crate::comment::{CommentType, Specialcomment};
+use crate::diagnostic::Diagnostic;
 
 pub struct CommentMap {
     map: HashMap<String, Vec<Specialcomment>>,
@@ -24,27 +25,62 @@ impl CommentMap {
         self.potentially_invalid = true;
     }
 
-    pub fn remove_incomplete(&mut self) {
+    /// remove sections with missing or duplicate markers
+    /// and report why they were removed
+    pub fn remove_incomplete(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
         let mut incomplete_sections = vec![];
         for (section, comments) in self.map.iter() {
-            if section == "all" {
-                continue;
-            }
             let mut incomplete = false;
-            let mut comment_types: HashSet<CommentType> = HashSet::new();
+            let mut comment_types: HashMap<CommentType, u32> = HashMap::new();
             for comment in comments {
                 // do not allow for multiple definitions of the same attribute
-                if comment_types.contains(&comment.comment_type) {
+                if let Some(firstline) = comment_types.get(&comment.comment_type) {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            "duplicate-marker",
+                            comment.line,
+                            &format!(
+                                "duplicate {} marker for section {}",
+                                Into::<String>::into(comment.comment_type.clone()),
+                                section
+                            ),
+                        )
+                        .with_related(*firstline, "first defined here"),
+                    );
                     incomplete = true;
-                    break;
+                    continue;
                 }
-                comment_types.insert(comment.comment_type.clone());
+                comment_types.insert(comment.comment_type.clone(), comment.line);
+            }
+
+            // file wide comments only use the first definition
+            if section == "all" {
+                continue;
             }
 
-            if !incomplete {
-                incomplete = !comment_types.contains(&CommentType::SectionBegin)
-                    || !comment_types.contains(&CommentType::HashInfo)
-                    || !comment_types.contains(&CommentType::SectionEnd);
+            for (comment_type, code) in [
+                (CommentType::SectionBegin, "missing-begin"),
+                (CommentType::HashInfo, "missing-hash"),
+                (CommentType::SectionEnd, "missing-end"),
+            ] {
+                if comment_types.contains_key(&comment_type) {
+                    continue;
+                }
+                incomplete = true;
+                let mut diagnostic = Diagnostic::error(
+                    code,
+                    comments[0].line,
+                    &format!(
+                        "section {} has no {} marker",
+                        section,
+                        Into::<String>::into(comment_type)
+                    ),
+                );
+                for comment in &comments[1..] {
+                    diagnostic = diagnostic.with_related(comment.line, "other marker of section");
+                }
+                diagnostics.push(diagnostic);
             }
             if incomplete {
                 incomplete_sections.push(section.clone());
@@ -55,6 +91,60 @@ impl CommentMap {
             self.remove_section(&section);
         }
         self.potentially_invalid = false;
+        diagnostics
+    }
+
+    /// drop info comments placed outside of their section
+    /// those lines are kept as regular content instead
+    pub fn remove_stray(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut broken_sections = Vec::new();
+        for (section, comments) in self.map.iter_mut() {
+            if section == "all" {
+                continue;
+            }
+            let find_line = |comment_type: CommentType| {
+                comments
+                    .iter()
+                    .find(|comment| comment.comment_type == comment_type)
+                    .map(|comment| comment.line)
+            };
+            if let (Some(begin), Some(end)) = (
+                find_line(CommentType::SectionBegin),
+                find_line(CommentType::SectionEnd),
+            ) {
+                if end < begin {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            "end-before-begin",
+                            end,
+                            &format!("section {} ends before it begins", section),
+                        )
+                        .with_related(begin, "section begins here"),
+                    );
+                    broken_sections.push(section.clone());
+                    continue;
+                }
+                comments.retain(|comment| {
+                    let inside = comment.line >= begin && comment.line <= end;
+                    if !inside {
+                        diagnostics.push(
+                            Diagnostic::warning(
+                                "stray-marker",
+                                comment.line,
+                                &format!("marker outside of section {}, ignoring it", section),
+                            )
+                            .with_related(begin, "section begins here"),
+                        );
+                    }
+                    inside
+                });
+            }
+        }
+        for section in broken_sections {
+            self.remove_section(&section);
+        }
+        diagnostics
     }
 
     pub fn remove_section(&mut self, section: &str) {
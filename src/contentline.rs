@@ -2,4 +2,3 @@ pub struct ContentLine {
     pub linenumber: u32,
     pub content: String,
 }
-
@@ -0,0 +1,199 @@
+use colored::Colorize;
+use serde_json::{json, Value};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            }
+        )
+    }
+}
+
+/// another line that helps explain a diagnostic
+#[derive(Clone, Debug)]
+pub struct RelatedLine {
+    pub line: u32,
+    pub label: String,
+    pub text: Option<String>, // source of the line, if known
+}
+
+/// a problem found while reading a file
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: String,
+    pub line: u32,
+    pub column: u32, // 1 based, counted in characters
+    pub code: &'static str,
+    pub message: String,
+    pub related: Vec<RelatedLine>,
+    pub text: Option<String>, // source of the line, if known
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, line: u32, message: &str) -> Diagnostic {
+        Diagnostic {
+            severity,
+            file: String::new(),
+            line,
+            column: 1,
+            code,
+            message: String::from(message),
+            related: Vec::new(),
+            text: None,
+        }
+    }
+
+    pub fn error(code: &'static str, line: u32, message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Error, code, line, message)
+    }
+
+    pub fn warning(code: &'static str, line: u32, message: &str) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, code, line, message)
+    }
+
+    pub fn with_column(mut self, column: u32) -> Diagnostic {
+        self.column = column;
+        self
+    }
+
+    pub fn with_related(mut self, line: u32, label: &str) -> Diagnostic {
+        self.related.push(RelatedLine {
+            line,
+            label: String::from(label),
+            text: None,
+        });
+        self
+    }
+
+    /// fill in the file name and the source text of all referenced lines
+    pub fn attach_source(&mut self, file: &str, lines: &[&str]) {
+        let get_line = |line: u32| {
+            lines
+                .get((line as usize).wrapping_sub(1))
+                .map(|text| String::from(*text))
+        };
+        self.file = String::from(file);
+        self.text = get_line(self.line);
+        for related in self.related.iter_mut() {
+            related.text = get_line(related.line);
+        }
+    }
+
+    /// render in the style of compiler errors, with source snippets
+    pub fn render(&self) -> String {
+        let width = self
+            .related
+            .iter()
+            .map(|related| related.line)
+            .chain([self.line])
+            .max()
+            .unwrap_or(0)
+            .to_string()
+            .len();
+        let gutter = " ".repeat(width);
+        let severity = match self.severity {
+            Severity::Error => self.severity.to_string().red().bold(),
+            Severity::Warning => self.severity.to_string().yellow().bold(),
+        };
+
+        let mut ret = format!("{}[{}]: {}\n", severity, self.code, self.message.bold());
+        ret.push_str(&format!(
+            "{}{} {}:{}:{}\n",
+            gutter,
+            "-->".blue().bold(),
+            self.file,
+            self.line,
+            self.column
+        ));
+        if let Some(text) = &self.text {
+            ret.push_str(&format!("{} {}\n", gutter, "|".blue().bold()));
+            ret.push_str(&format!(
+                "{:>width$} {} {}\n",
+                self.line.to_string().blue().bold(),
+                "|".blue().bold(),
+                text,
+                width = width
+            ));
+            ret.push_str(&format!(
+                "{} {} {}{}\n",
+                gutter,
+                "|".blue().bold(),
+                " ".repeat(self.column.saturating_sub(1) as usize),
+                "^".bold()
+            ));
+        }
+        for related in &self.related {
+            match &related.text {
+                Some(text) => {
+                    ret.push_str(&format!(
+                        "{:>width$} {} {}\n",
+                        related.line.to_string().blue().bold(),
+                        "|".blue().bold(),
+                        text,
+                        width = width
+                    ));
+                    ret.push_str(&format!(
+                        "{} {} {}\n",
+                        gutter,
+                        "|".blue().bold(),
+                        format!("- {}", related.label).blue()
+                    ));
+                }
+                None => {
+                    ret.push_str(&format!(
+                        "{} {} {} (line {})\n",
+                        gutter,
+                        "=".blue().bold(),
+                        related.label,
+                        related.line
+                    ));
+                }
+            }
+        }
+        ret
+    }
+
+    /// single line in the default vim errorformat, for :cfile and friends
+    pub fn quickfix(&self) -> String {
+        format!(
+            "{}:{}:{}: {}: {} [{}]",
+            self.file, self.line, self.column, self.severity, self.message, self.code
+        )
+    }
+
+    pub fn json(&self) -> Value {
+        let related = self
+            .related
+            .iter()
+            .map(|related| json!({"line": related.line, "label": related.label}))
+            .collect::<Vec<Value>>();
+        json!({
+            "file": self.file,
+            "line": self.line,
+            "column": self.column,
+            "severity": self.severity.to_string(),
+            "code": self.code,
+            "message": self.message,
+            "related": related,
+        })
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.render())
+    }
+}
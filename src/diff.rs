@@ -0,0 +1,136 @@
+use crate::merge::matching_lines;
+
+// lines of unchanged context around every change
+const CONTEXT: usize = 3;
+
+enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// unified diff from old to new, empty if both are the same
+pub fn unified_diff(old: &str, new: &str, oldname: &str, newname: &str) -> String {
+    labeled_diff(old, new, oldname, newname, &[], &[])
+}
+
+/// unified diff with the label of the first changed line after every hunk header,
+/// like git names the function a hunk is in
+/// labels are given per line of old and new
+pub fn labeled_diff(
+    old: &str,
+    new: &str,
+    oldname: &str,
+    newname: &str,
+    oldlabels: &[Option<String>],
+    newlabels: &[Option<String>],
+) -> String {
+    let oldlines: Vec<&str> = old.split_inclusive('\n').collect();
+    let newlines: Vec<&str> = new.split_inclusive('\n').collect();
+    let matches = matching_lines(&oldlines, &newlines);
+
+    let mut script = Vec::new();
+    let mut j = 0;
+    for (i, line) in oldlines.iter().enumerate() {
+        match matches[i] {
+            Some(k) => {
+                script.extend(newlines[j..k].iter().map(|line| Line::Added(line)));
+                script.push(Line::Same(line));
+                j = k + 1;
+            }
+            None => script.push(Line::Removed(line)),
+        }
+    }
+    script.extend(newlines[j..].iter().map(|line| Line::Added(line)));
+
+    // runs of consecutive changed lines
+    let mut changes: Vec<(usize, usize)> = Vec::new();
+    for (index, line) in script.iter().enumerate() {
+        if matches!(line, Line::Same(_)) {
+            continue;
+        }
+        match changes.last_mut() {
+            Some((_, end)) if *end == index => *end += 1,
+            _ => changes.push((index, index + 1)),
+        }
+    }
+    if changes.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<Option<&String>> = changes
+        .iter()
+        .map(|(change, _)| {
+            let (oldline, newline) = counts(&script[..*change]);
+            let label = match script[*change] {
+                Line::Removed(_) => oldlabels.get(oldline),
+                _ => newlabels.get(newline),
+            };
+            label.and_then(Option::as_ref)
+        })
+        .collect();
+
+    let mut ret = format!("--- {}\n+++ {}\n", oldname, newname);
+    let (mut first, mut previous_end) = (0, 0);
+    while first < changes.len() {
+        // changes closer than two contexts share a hunk, unless they
+        // are in different sections
+        let mut last = first;
+        while last + 1 < changes.len()
+            && changes[last + 1].0 - changes[last].1 <= 2 * CONTEXT
+            && labels[last + 1] == labels[first]
+        {
+            last += 1;
+        }
+        // context is shortened where hunks would overlap
+        let start = changes[first].0.saturating_sub(CONTEXT).max(previous_end);
+        let mut end = (changes[last].1 + CONTEXT).min(script.len());
+        if let Some((next, _)) = changes.get(last + 1) {
+            end = end.min(*next);
+        }
+        ret.push_str(&hunk(&script, start, end, labels[first]));
+        (first, previous_end) = (last + 1, end);
+    }
+    ret
+}
+
+// number of old and new lines in a part of the script
+fn counts(lines: &[Line]) -> (usize, usize) {
+    lines.iter().fold((0, 0), |(old, new), line| match line {
+        Line::Same(_) => (old + 1, new + 1),
+        Line::Removed(_) => (old + 1, new),
+        Line::Added(_) => (old, new + 1),
+    })
+}
+
+fn hunk(script: &[Line], start: usize, end: usize, label: Option<&String>) -> String {
+    let (oldstart, newstart) = counts(&script[..start]);
+    let (oldcount, newcount) = counts(&script[start..end]);
+    // an empty range names the line before it
+    let range = |start: usize, count: usize| {
+        format!("{},{}", if count == 0 { start } else { start + 1 }, count)
+    };
+
+    let mut ret = format!(
+        "@@ -{} +{} @@",
+        range(oldstart, oldcount),
+        range(newstart, newcount)
+    );
+    if let Some(label) = label {
+        ret.push(' ');
+        ret.push_str(label);
+    }
+    ret.push('\n');
+    for line in &script[start..end] {
+        let (prefix, text) = match line {
+            Line::Same(text) => (' ', text),
+            Line::Removed(text) => ('-', text),
+            Line::Added(text) => ('+', text),
+        };
+        ret.push(prefix);
+        ret.push_str(text);
+        if !text.ends_with('\n') {
+            ret.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+    ret
+}
@@ -1,22 +1,37 @@
-use crate::comment::{CommentType, Specialcomment};
+use crate::atomicwrite::write_atomic;
+use crate::command::{self, CommandPolicy};
+use crate::comment::{
+    parse_permissions, permission_mode, CommentSyntax, CommentType, Specialcomment,
+};
 use crate::commentmap::CommentMap;
 use crate::contentline::ContentLine;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::diff::{labeled_diff, unified_diff};
 use crate::hashable::Hashable;
+use crate::journal::{display_path, JournalEntry};
+use crate::languages::{LanguageRegistry, SyntaxMatch};
+use crate::merge::merge;
 use crate::metafile::MetaFile;
-use crate::section::{NamedSectionData, Section, SectionData};
+use crate::outdated::{Freshness, SectionStatus};
+use crate::section::{
+    expanded_content, line_ending, MarkerStyle, NamedSectionData, Section, SectionData,
+};
+use crate::session::Session;
+use crate::source::{
+    content_digest, is_valid_pin, matches_pin, SourceContent, SourceLocation, SourceUri,
+};
+use crate::store::ObjectStore;
 use colored::Colorize;
-use regex::Regex;
-use std::clone;
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
+use sha256::digest;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions};
 
+use std::fmt;
 use std::io::prelude::*;
-use std::io::{self, ErrorKind};
-use std::ops::Deref;
-use std::os::unix::prelude::PermissionsExt;
+use std::io::{self, ErrorKind, IsTerminal};
 use std::path::{Path, PathBuf};
 use std::string::String;
+use std::time::Duration;
 
 pub enum ApplyResult {
     Changed,
@@ -24,17 +39,52 @@ pub enum ApplyResult {
     Error,
 }
 
+/// what applying a file would do to its target
+pub enum ApplyPlan {
+    /// the target does not exist, it is created from the source as shown
+    Create(DotFile),
+    /// the target with the sections of the source applied
+    Update(DotFile),
+    Unchanged,
+}
+
+/// what to do if a section the user changed cannot be merged with upstream cleanly
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictStyle {
+    /// report the conflicts and leave the section alone
+    #[default]
+    Report,
+    /// apply the merge with conflict markers for the user to resolve
+    Markers,
+}
+
+/// what update may do to reach sources
+#[derive(Default)]
+pub struct UpdateOptions {
+    pub offline: bool,    // only use cached copies of remote sources
+    pub trust: bool,      // run commands of files outside trusted directories without asking
+    pub accept_new: bool, // take content that does not match its sha256 pin and rewrite the pin
+    pub conflicts: ConflictStyle,
+    pub commands: CommandPolicy,
+    pub dry_run: bool, // write nothing, not even caches, and run no commands
+}
+
 pub struct DotFile {
     //TODO maybe implement finalize?
     specialcomments: Vec<Specialcomment>,
     pub sections: Vec<Section>,
-    pub file: File,
     pub filename: String,
     pub targetfile: Option<String>,
     pub metafile: Option<MetaFile>,
-    pub commentsign: String,
+    pub commentsign: CommentSyntax,
+    pub language: Option<SyntaxMatch>, // how the comment syntax was detected
     pub modified: bool,
     pub permissions: Option<u32>,
+    pub bom: bool, // file starts with a utf-8 byte order mark
+    pub markerstyle: MarkerStyle,
+    pub diagnostics: Vec<Diagnostic>, // problems found while parsing
+    bases: Vec<String>, // upstream content merged with, stored once the file is written
+    sources: Vec<String>, // uris of the sources an update took content from, for the journal
 }
 
 impl DotFile {
@@ -44,21 +94,14 @@ impl DotFile {
     }
 
     pub fn from_pathbuf(path: &PathBuf) -> Result<DotFile, std::io::Error> {
-        let sourcepath = path
-            .canonicalize()
-            .expect("could not canonicalize path")
-            .display()
-            .to_string();
+        let sourcepath = path.canonicalize()?.display().to_string();
 
         let sourcefile = match OpenOptions::new().read(true).write(true).open(path) {
             Err(e) => {
                 if e.kind() == ErrorKind::PermissionDenied {
                     // open file as readonly if writing is not permitted
                     // TODO: skip readonly files entirely
-                    match OpenOptions::new().read(true).write(false).open(path) {
-                        Ok(file) => file,
-                        Err(error) => return Err(error),
-                    }
+                    OpenOptions::new().read(true).write(false).open(path)?
                 } else {
                     return Err(e);
                 }
@@ -68,25 +111,12 @@ impl DotFile {
 
         let metafile;
 
-        let mut comments = Vec::new();
-        let mut line_counter = 0;
-
-        let mut sections: Vec<Section> = Vec::new();
-        let mut lines: Vec<ContentLine> = Vec::new();
-
-        let mut comment_map: CommentMap = CommentMap::new();
-        let mut section_map: HashMap<String, Vec<Specialcomment>> = HashMap::new();
-
-        let mut target_file: Option<String> = Option::None;
-        let mut permissions = Option::None;
-        let mut commentsign = String::new();
-        let mut hascommentsign = false;
+        // read the file as is, line endings and all
+        let mut content = String::new();
+        io::BufReader::new(&sourcefile).read_to_string(&mut content)?;
 
         // check for metafile
         if Path::new(&format!("{}.imosid.toml", sourcepath)).is_file() {
-            let mut content = String::new();
-            io::BufReader::new(&sourcefile).read_to_string(&mut content)?;
-
             metafile = if let Some(mut metafile) = MetaFile::new(
                 PathBuf::from(&format!("{}.imosid.toml", sourcepath)),
                 &content,
@@ -94,48 +124,85 @@ impl DotFile {
                 metafile.finalize();
                 metafile
             } else {
-                return Err(std::io::Error::new(ErrorKind::Other, "invalid metafile"));
+                return Err(std::io::Error::other("invalid metafile"));
             };
             return Ok(DotFile {
-                specialcomments: comments,
-                sections,
-                file: sourcefile,
+                specialcomments: Vec::new(),
+                sections: Vec::new(),
                 filename: sourcepath,
                 targetfile: metafile.targetfile.clone(),
                 modified: metafile.modified,
-                permissions: metafile.permissions.clone(),
+                permissions: metafile.permissions,
                 metafile: Some(metafile),
-                commentsign: String::from(""),
+                commentsign: CommentSyntax::default(),
+                language: None,
+                bom: false,
+                markerstyle: MarkerStyle::Expanded,
+                diagnostics: Vec::new(),
+                bases: Vec::new(),
+                sources: Vec::new(),
             });
         }
 
-        let filelines = io::BufReader::new(&sourcefile).lines();
+        Ok(DotFile::parse(sourcepath, &content))
+    }
+
+    /// parse content that is not read from a file on disk, like a file in a git repository
+    /// the name is used to detect the comment syntax
+    pub fn from_content(name: &str, content: &str) -> DotFile {
+        DotFile::parse(String::from(name), content)
+    }
+
+    fn parse(sourcepath: String, content: &str) -> DotFile {
+        let mut comments = Vec::new();
+
+        let mut sections: Vec<Section> = Vec::new();
+        let mut lines: Vec<ContentLine> = Vec::new();
+
+        let mut comment_map: CommentMap = CommentMap::new();
+
+        let mut target_file: Option<String> = Option::None;
+        let mut permissions = Option::None;
+
+        let bom = content.starts_with(BOM);
+        let body = content.strip_prefix(BOM).unwrap_or(content);
+
+        let rawlines = body.split_inclusive('\n').collect::<Vec<&str>>();
+        let textlines = rawlines
+            .iter()
+            .map(|line| strip_line_ending(line))
+            .collect::<Vec<&str>>();
+        let language = LanguageRegistry::global().detect(&sourcepath, &textlines);
+        let mut diagnostics = Vec::new();
+        let commentsign = language.syntax.clone();
+        let mut markerstyle = MarkerStyle::Expanded;
+
         // parse lines for special comments
-        for i in filelines {
-            line_counter += 1;
-            let line = i?;
-            // TODO: Do this better
-            if !hascommentsign {
-                commentsign = String::from(get_comment_sign(&sourcepath, &line));
-                hascommentsign = true;
+        for (index, rawline) in rawlines.into_iter().enumerate() {
+            let line_counter = index as u32 + 1;
+            let line = strip_line_ending(rawline);
+
+            let linecomments = Specialcomment::from_line(line, &commentsign, line_counter)
+                .unwrap_or_else(|diagnostic| {
+                    diagnostics.push(*diagnostic);
+                    Vec::new()
+                });
+            if linecomments.len() > 1 {
+                markerstyle = MarkerStyle::Compact;
             }
-
-            let newcomment = Specialcomment::from_line(&line, &commentsign, line_counter);
-            match newcomment {
-                Some(comment) => {
-                    // comments with section all apply to the entire file
-                    //TODO: move checking into comment from_line
-                    comment_map.push_comment(comment.clone());
-                    comments.push(comment.clone());
-                }
-                None => lines.push(ContentLine {
-                    linenumber: line_counter,
-                    content: line,
-                }),
+            for comment in linecomments {
+                // comments with section all apply to the entire file
+                comment_map.push_comment(comment.clone());
+                comments.push(comment);
             }
+            lines.push(ContentLine {
+                linenumber: line_counter,
+                content: String::from(rawline),
+            });
         }
 
-        comment_map.remove_incomplete();
+        diagnostics.extend(comment_map.remove_stray());
+        diagnostics.extend(comment_map.remove_incomplete());
 
         if let Some(comment) = comment_map.get_comment("all", CommentType::TargetInfo) {
             if let Some(arg) = &comment.argument {
@@ -144,120 +211,154 @@ impl DotFile {
         }
         if let Some(comment) = comment_map.get_comment("all", CommentType::PermissionInfo) {
             if let Some(arg) = &comment.argument {
-                permissions = match arg.split_at(3).1.parse::<u32>() {
-                    Err(_) => Option::None,
-                    Ok(permnumber) => Option::Some(permnumber),
-                }
+                permissions = parse_permissions(arg)
             }
         }
 
         for sectionname in comment_map.get_sections() {
-            Section::from_comment_map(sectionname, &comment_map).map(|section| {
+            if let Some(section) = Section::from_comment_map(sectionname, &comment_map) {
                 sections.push(section);
-            });
+            }
         }
 
-        // sort sections by lines (retaining the original order of the file)
-        sections.sort_by(|a, b| a.get_data().startline.cmp(&b.get_data().startline));
-
-        // detect overlapping sections
-        let vecsize = sections.len();
-        let mut broken_indices = Vec::new();
-        let mut skipnext = false;
-        for i in 0..vecsize {
-            if skipnext {
-                skipnext = false;
+        // sources are resolved relative to the file declaring them
+        let basedir = source_base(&sourcepath);
+        for sectionname in comment_map.get_sections() {
+            let Some(comment) = comment_map.get_comment(sectionname, CommentType::SourceInfo)
+            else {
                 continue;
+            };
+            let Some(source) = &comment.argument else {
+                continue;
+            };
+            match SourceUri::parse(source, &basedir) {
+                Err(e) => diagnostics.push(Diagnostic::error("invalid-source", comment.line, &e)),
+                Ok(uri) => {
+                    if let Err(e) = uri.check() {
+                        diagnostics.push(Diagnostic::warning(
+                            "unresolved-source",
+                            comment.line,
+                            &e,
+                        ));
+                    }
+                }
             }
-            let currentsection = &sections[i];
-            if i < vecsize - 1 {
-                let nextsection = &sections[i + 1];
-                if nextsection.get_data().startline < currentsection.get_data().endline {
-                    broken_indices.push(i + 1);
-                    broken_indices.push(i);
-                    skipnext = true;
+            for (_, pin) in comment.options.iter().filter(|(key, _)| key == "sha256") {
+                if !is_valid_pin(pin) {
+                    diagnostics.push(Diagnostic::error(
+                        "invalid-pin",
+                        comment.line,
+                        "sha256 pin has to be 64 hexadecimal digits",
+                    ));
                 }
             }
         }
 
-        for i in broken_indices {
-            println!("section {} overlapping", i);
-            sections.remove(i);
-        }
+        // sort sections by lines (retaining the original order of the file)
+        sections.sort_by_key(|section| section.get_data().startline);
 
-        let mut modified = false;
-        // introduce anonymous sections
-        if sections.len() > 0 {
-            let mut currentline = 1;
-            let mut tmpstart;
-            let mut tmpend;
-            let mut anonymous_sections: Vec<Section> = Vec::new();
-            for i in &sections {
-                if i.get_data().startline - currentline >= 1 {
-                    tmpstart = currentline;
-                    tmpend = i.get_data().startline - 1;
-                    let newsection = Section::new_anonymous(tmpstart, tmpend);
-                    anonymous_sections.push(newsection);
-                }
-                currentline = i.get_data().endline + 1;
+        // sections may be nested in each other, but not overlap partially
+        let mut broken_sections = Vec::new();
+        let mut open: Vec<(&SectionData, &NamedSectionData)> = Vec::new();
+        for section in &sections {
+            let Section::Named(data, named_data) = section else {
+                continue;
+            };
+            while open
+                .last()
+                .is_some_and(|(parent, _)| parent.endline < data.startline)
+            {
+                open.pop();
             }
-
-            sections.extend(anonymous_sections);
-            sections.sort_by(|a, b| a.get_data().startline.cmp(&b.get_data().startline));
-        } else {
-            // make the entire file one anonymous section
-            let newsection = Section::new_anonymous(1, lines.len() as u32);
-            sections.push(newsection);
-        }
-
-        // fill sections with content
-        for i in &mut sections {
-            // TODO: speed this up, binary search or something
-            for c in &lines {
-                if c.linenumber > i.get_data().endline {
-                    break;
-                } else if c.linenumber < i.get_data().startline {
+            if let Some((parent, parent_named)) = open.last() {
+                if data.endline > parent.endline {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            "overlapping-sections",
+                            data.startline,
+                            &format!(
+                                "section {} overlaps section {}, ignoring both",
+                                named_data.name, parent_named.name
+                            ),
+                        )
+                        .with_related(
+                            parent.startline,
+                            &format!("{} begins here", parent_named.name),
+                        )
+                        .with_related(parent.endline, &format!("{} ends here", parent_named.name)),
+                    );
+                    broken_sections.push(parent_named.name.clone());
+                    broken_sections.push(named_data.name.clone());
                     continue;
                 }
-                i.push_str(&c.content);
             }
-            i.finalize();
-            //TODO: deal with "modified" variable
+            open.push((data, named_data));
+        }
+
+        for name in &broken_sections {
+            comment_map.remove_section(name);
+        }
+        sections.retain(|section| match section {
+            Section::Named(_, named_data) => !broken_sections.contains(&named_data.name),
+            Section::Anonymous(_) => true,
+        });
+
+        // lines holding markers of valid sections are not part of any content
+        let mut marker_lines = HashSet::new();
+        for sectionname in comment_map.get_sections() {
+            for comment in comment_map.get_comments(sectionname).unwrap() {
+                marker_lines.insert(comment.line);
+            }
+        }
+
+        // fill sections with content and introduce anonymous sections
+        // for everything between named sections
+        let sections = fill_sections(&sections, 1, lines.len() as u32, "", &lines, &marker_lines);
+        let modified = sections.iter().any(Section::is_modified);
+
+        diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+        for diagnostic in diagnostics.iter_mut() {
+            diagnostic.attach_source(&sourcepath, &textlines);
         }
 
-        let retfile = DotFile {
+        DotFile {
             specialcomments: comments,
             sections,
-            file: sourcefile,
             filename: sourcepath,
             targetfile: target_file,
             commentsign,
+            language: Some(language),
             metafile: None,
             modified,
             permissions,
-        };
+            bom,
+            markerstyle,
+            diagnostics,
+            bases: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
 
-        return Ok(retfile);
+    /// print diagnostics of at least the given severity to stderr
+    pub fn print_diagnostics(&self, severity: Severity) {
+        for diagnostic in &self.diagnostics {
+            if diagnostic.severity >= severity {
+                eprintln!("{}", diagnostic);
+            }
+        }
     }
 
+    // all named sections, nested ones included
     fn get_named_sections(&self) -> Vec<(&SectionData, &NamedSectionData)> {
         let mut retvec: Vec<(&SectionData, &NamedSectionData)> = Vec::new();
         for i in &self.sections {
-            if let Section::Named(data, named_data) = i {
-                retvec.push((&data, &named_data));
-            }
+            retvec.extend(i.named_sections());
         }
         return retvec;
     }
 
     pub fn count_named_sections(&self) -> u32 {
-        let mut counter = 0;
-        for i in &self.sections {
-            if let Section::Named { .. } = i {
-                counter += 1;
-            }
-        }
-        counter
+        self.get_named_sections().len() as u32
     }
 
     pub fn is_managed(&self) -> bool {
@@ -277,10 +378,17 @@ impl DotFile {
                 retstring.push_str(&metafile.pretty_info());
             } // TODO
             None => {
-                retstring.push_str(&format!("comment syntax: {}\n", self.commentsign));
+                match &self.language {
+                    Some(language) => {
+                        retstring.push_str(&format!("comment syntax: {}\n", language));
+                    }
+                    None => {
+                        retstring.push_str(&format!("comment syntax: {}\n", self.commentsign));
+                    }
+                }
                 for section in self.sections.iter() {
                     if let Some(section_info) = &section.pretty_info() {
-                        retstring.push_str(&section_info);
+                        retstring.push_str(section_info);
                         retstring.push('\n');
                     }
                 }
@@ -300,69 +408,394 @@ impl DotFile {
         return retstring;
     }
 
-    pub fn update(&mut self) {
-        //iterate over sections in self.sections
-
-        let mut modified = false;
-        let mut applymap: HashMap<&String, DotFile> = HashMap::new();
-        let mut source_sections = Vec::new();
-        if self.metafile.is_some() {
-            let metafile = &self.metafile.as_ref().unwrap();
-            if metafile.modified {
-                return;
-            }
-            if !metafile.sourcefile.is_some() {
-                return;
-            }
-            //TODO look up what as_ref does
-            match DotFile::new(&metafile.sourcefile.as_ref().unwrap()) {
-                Ok(file) => {
-                    modified = self.applyfile(&file);
+    /// update sections from their sources, all of them if no names are given
+    /// changes of the user are merged with upstream, reports go to stderr
+    /// return false if nothing has changed
+    pub fn update(&mut self, names: &[&str], options: &UpdateOptions, session: &Session) -> bool {
+        if let Some(metafile) = &self.metafile {
+            let Some(source) = metafile.sourcefile.clone() else {
+                eprintln!("{} has no source", self.filename.yellow());
+                return false;
+            };
+            let uri = match SourceUri::parse(&source, &source_base(&self.filename)) {
+                Ok(uri) if uri.section.is_some() => {
+                    eprintln!(
+                        "source {} of a metafile cannot name a section",
+                        source.red()
+                    );
+                    return false;
                 }
+                Ok(uri) => uri,
                 Err(e) => {
-                    println!("failed to apply metafile sourfe, error: {}", e);
+                    eprintln!("invalid source {}: {}", source.red(), e);
+                    return false;
+                }
+            };
+            let content = if let SourceLocation::Command(command) = &uri.location {
+                let mut diagnostics = Vec::new();
+                let output = command_source(
+                    &self.filename,
+                    command,
+                    options.commands.timeout,
+                    0,
+                    options,
+                    &mut diagnostics,
+                );
+                self.report(diagnostics);
+                let Some(text) = output else {
+                    return false;
+                };
+                SourceContent {
+                    text,
+                    revision: None,
+                }
+            } else {
+                match uri.read(options.offline, options.dry_run, session) {
+                    Ok(content) => content,
+                    Err(e) => {
+                        eprintln!("could not read source {}: {}", source.red(), e);
+                        return false;
+                    }
+                }
+            };
+            let Some(metafile) = &mut self.metafile else {
+                return false;
+            };
+            let upstreamhash = digest(content.text.as_str()).to_uppercase();
+            let digest = content_digest(&content.text);
+            let mut sourcepin = metafile.sourcepin.clone();
+            if let Some(pin) = &sourcepin {
+                if !matches_pin(pin, &digest) {
+                    if !options.accept_new {
+                        eprintln!(
+                            "{} {} does not match the pinned sha256 of {}, use --accept-new to accept it",
+                            "refusing".red().bold(),
+                            source.bold(),
+                            self.filename.bold()
+                        );
+                        return false;
+                    }
+                    sourcepin = Some(digest);
+                }
+            }
+            if upstreamhash == metafile.hash
+                && content.revision == metafile.sourcecommit
+                && sourcepin == metafile.sourcepin
+            {
+                eprintln!("{} already up to date", self.filename.bold());
+                return false;
+            }
+            if upstreamhash != metafile.hash {
+                if metafile.modified {
+                    if !merge_metafile(
+                        &self.filename,
+                        metafile,
+                        &content.text,
+                        options.conflicts,
+                        &session.store,
+                    ) {
+                        return false;
+                    }
+                    self.bases.push(content.text.clone());
+                } else {
+                    metafile.content = content.text;
+                    metafile.compile();
                 }
             }
-            return;
+            metafile.sourcecommit = content.revision;
+            metafile.sourcepin = sourcepin;
+            self.sources.push(uri.to_string());
+            eprintln!("updated {} from {}", self.filename.bold(), source.bold());
+            return true;
         }
 
-        for section in &self.sections {
-            if let Section::Named(data, named_data) = section {
-                if let Some(source) = &named_data.source {
-                    if !applymap.contains_key(source) {
-                        match DotFile::new(source) {
-                            Ok(sfile) => {
-                                applymap.insert(source, sfile);
-                            }
-                            Err(_) => {
-                                println!("error: could not open source file {}", source);
-                                continue;
-                            }
+        for name in names {
+            if self.get_section(name).is_none() {
+                eprintln!("could not find section {}", name.red());
+            }
+        }
+
+        let basedir = source_base(&self.filename);
+        let mut sourcefiles = SourceCache::new();
+        let mut updates = Vec::new();
+        let mut diagnostics = Vec::new();
+        for (targetdata, named_data) in self.get_named_sections() {
+            let selected = names.contains(&named_data.name.as_str());
+            if !names.is_empty() && !selected {
+                continue;
+            }
+            let Some(source) = &named_data.source else {
+                if selected {
+                    eprintln!("section {} has no source", named_data.name.yellow());
+                }
+                continue;
+            };
+            let uri = match SourceUri::parse(source, &basedir) {
+                Ok(uri) => uri,
+                Err(e) => {
+                    eprintln!("invalid source {}: {}", source.red(), e);
+                    continue;
+                }
+            };
+            let (data, source_named_data, revision) = if let SourceLocation::Command(command) =
+                &uri.location
+            {
+                let timeout = match named_data
+                    .source_options
+                    .iter()
+                    .find(|(key, _)| key == "timeout")
+                {
+                    Some((_, seconds)) => match seconds.parse::<u64>() {
+                        Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                        _ => {
+                            eprintln!("invalid timeout {} of {}", seconds.red(), source.bold());
+                            continue;
                         }
+                    },
+                    None => options.commands.timeout,
+                };
+                let Some(output) = command_source(
+                    &self.filename,
+                    command,
+                    timeout,
+                    targetdata.startline,
+                    options,
+                    &mut diagnostics,
+                ) else {
+                    continue;
+                };
+                let mut section = Section::new(0, 0, named_data.name.clone(), None, String::new());
+                section.push_str(&output);
+                section.finalize();
+                let Section::Named(data, source_named_data) = section else {
+                    continue;
+                };
+                (data, source_named_data, None)
+            } else {
+                match upstream_section(
+                    &uri,
+                    &named_data.name,
+                    &mut sourcefiles,
+                    options.offline,
+                    options.dry_run,
+                    session,
+                ) {
+                    Ok(upstream) => upstream,
+                    Err(e) => {
+                        eprintln!("{}", e.red());
+                        continue;
+                    }
+                }
+            };
+
+            // refuse content that is not what the pin says
+            let mut source_options = named_data.source_options.clone();
+            if let Some(pin) = named_data.source_pin() {
+                let digest = content_digest(&expanded_content(&data, &source_named_data));
+                if !matches_pin(pin, &digest) {
+                    if !options.accept_new {
+                        diagnostics.push(Diagnostic::error(
+                            "pin-mismatch",
+                            targetdata.startline,
+                            &format!(
+                                "content of {} does not match the pinned sha256, use --accept-new to accept it",
+                                source
+                            ),
+                        ));
+                        continue;
                     }
-                    if let Some(sfile) = applymap.get(source) {
-                        source_sections.push(sfile.clone().get_section(source).unwrap());
+                    for (key, value) in source_options.iter_mut() {
+                        if key == "sha256" {
+                            *value = digest.clone();
+                        }
                     }
                 }
             }
-        }
 
-        for applysection in source_sections.iter() {
-            if let Section::Named(data, named_data) = applysection.clone() {
-                self.applysection(data, named_data);
+            // pin the commit the content was taken from
+            source_options.retain(|(key, _)| key != "commit");
+            if let Some(revision) = revision {
+                source_options.push((String::from("commit"), revision));
+            }
+            if source_named_data.hash == named_data.targethash
+                && source_options == named_data.source_options
+            {
+                eprintln!("section {} already up to date", named_data.name.bold());
+                continue;
+            }
+
+            // the section keeps its name and source, content and hash come from upstream
+            let mut newdata = source_named_data;
+            newdata.name = named_data.name.clone();
+            newdata.source = named_data.source.clone();
+            newdata.source_options = source_options;
+            newdata.targethash = newdata.hash.clone();
+            newdata.raw = None;
+            updates.push((data, newdata, uri.to_string()));
+        }
+        self.report(diagnostics);
+
+        let mut changed = false;
+        for (data, named_data, uri) in updates {
+            let name = named_data.name.clone();
+            if self.applysection(data, named_data, options.conflicts, &session.store) {
+                self.sources.push(uri);
+                eprintln!("updated section {}", name.green());
+                changed = true;
             }
         }
+        if changed {
+            self.modified = self.sections.iter().any(Section::is_modified);
+        }
+        changed
     }
 
-    fn get_section(&self, name: &str) -> Option<Section> {
-        for i in &self.sections {
-            if let Section::Named(_, named_data) = i {
-                if named_data.name == name {
-                    return Some(i.clone());
+    /// compare sections with their sources without changing anything
+    /// command sources are not run and reported as skipped
+    pub fn outdated(&self, offline: bool, session: &Session) -> Vec<SectionStatus> {
+        let basedir = source_base(&self.filename);
+        let status = |section: Option<&str>, source: &str, freshness, message: Option<String>| {
+            SectionStatus {
+                file: self.filename.clone(),
+                section: section.map(String::from),
+                source: String::from(source),
+                freshness,
+                message,
+            }
+        };
+
+        if let Some(metafile) = &self.metafile {
+            let Some(source) = &metafile.sourcefile else {
+                return Vec::new();
+            };
+            let upstream = match SourceUri::parse(source, &basedir) {
+                Ok(uri) if matches!(uri.location, SourceLocation::Command(_)) => {
+                    return vec![status(None, source, Freshness::Skipped, None)];
+                }
+                Ok(uri) => uri.read(offline, true, session),
+                Err(e) => Err(e),
+            };
+            return vec![match upstream {
+                Ok(content) => {
+                    let changed = digest(content.text).to_uppercase() != metafile.hash;
+                    status(
+                        None,
+                        source,
+                        Freshness::classify(metafile.modified, changed),
+                        None,
+                    )
+                }
+                Err(e) => status(None, source, Freshness::Unknown, Some(e)),
+            }];
+        }
+
+        let mut sourcefiles = SourceCache::new();
+        let mut ret = Vec::new();
+        for (_, named_data) in self.get_named_sections() {
+            let Some(source) = &named_data.source else {
+                continue;
+            };
+            let name = Some(named_data.name.as_str());
+            let upstream = match SourceUri::parse(source, &basedir) {
+                Ok(uri) if matches!(uri.location, SourceLocation::Command(_)) => {
+                    ret.push(status(name, source, Freshness::Skipped, None));
+                    continue;
                 }
+                Ok(uri) => upstream_section(
+                    &uri,
+                    &named_data.name,
+                    &mut sourcefiles,
+                    offline,
+                    true,
+                    session,
+                ),
+                Err(e) => Err(e),
+            };
+            ret.push(match upstream {
+                Ok((_, upstream_data, _)) => status(
+                    name,
+                    source,
+                    Freshness::classify(
+                        named_data.is_modified(),
+                        upstream_data.hash != named_data.targethash,
+                    ),
+                    None,
+                ),
+                Err(e) => status(name, source, Freshness::Unknown, Some(e)),
+            });
+        }
+        ret
+    }
+
+    /// hashes of the contents the file was compiled with,
+    /// the store has to keep them to diff and merge against
+    pub fn stored_hashes(&self) -> Vec<String> {
+        match &self.metafile {
+            Some(metafile) => vec![metafile.hash.clone()],
+            None => self
+                .get_named_sections()
+                .into_iter()
+                .map(|(_, named_data)| named_data.targethash.clone())
+                .collect(),
+        }
+    }
+
+    /// unified diff of the changes of the user since the file was compiled
+    /// changes whose original is not stored are reported on stderr
+    pub fn original_diff(&self, store: &ObjectStore) -> String {
+        let changed: Vec<(String, &str, &str)> = match &self.metafile {
+            Some(metafile) if metafile.modified => vec![(
+                self.filename.clone(),
+                metafile.hash.as_str(),
+                metafile.content.as_str(),
+            )],
+            Some(_) => Vec::new(),
+            None => self
+                .get_named_sections()
+                .into_iter()
+                .filter(|(_, named_data)| named_data.is_modified())
+                .map(|(data, named_data)| {
+                    (
+                        format!("{} section {}", self.filename, named_data.name),
+                        named_data.targethash.as_str(),
+                        data.content.as_str(),
+                    )
+                })
+                .collect(),
+        };
+
+        let mut ret = String::new();
+        for (name, hash, content) in changed {
+            match store.get(hash) {
+                Some(original) => ret.push_str(&unified_diff(
+                    &original,
+                    content,
+                    &format!("{} (original)", name),
+                    &name,
+                )),
+                None => eprintln!(
+                    "{} {} was changed, but its original {} is not stored",
+                    "cannot diff".red(),
+                    name.bold(),
+                    hash
+                ),
             }
         }
-        None
+        ret
+    }
+
+    // print diagnostics found while updating and keep them with the file
+    // lines refer to the file as it was read
+    fn report(&mut self, mut diagnostics: Vec<Diagnostic>) {
+        for diagnostic in diagnostics.iter_mut() {
+            diagnostic.file = self.filename.clone();
+            eprintln!("{}", diagnostic);
+        }
+        self.diagnostics.extend(diagnostics);
+    }
+
+    pub fn get_section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find_map(|section| section.find(name))
     }
 
     // delete section sectionname from sections
@@ -374,9 +807,17 @@ impl DotFile {
             self.sections.remove(index);
             println!("deleting section {}", sectionname);
             return true;
-        } else {
-            return false;
         }
+        // nested sections are removed from their parent
+        if self
+            .sections
+            .iter_mut()
+            .any(|section| section.remove_nested(sectionname))
+        {
+            println!("deleting section {}", sectionname);
+            return true;
+        }
+        return false;
     }
 
     //TODO: changedstatus
@@ -395,118 +836,354 @@ impl DotFile {
         didsomething
     }
 
-    pub fn write_to_file(&mut self) {
-        let targetname = &expand_tilde(&self.filename);
-        let newfile = File::create(targetname);
-        match newfile {
-            Err(_) => {
-                println!("error: could not write to file {}", &self.filename);
-                panic!("write_to_file");
+    /// write the markers of every section in the given style,
+    /// returns false if the file already uses it
+    pub fn set_marker_style(&mut self, style: MarkerStyle) -> bool {
+        if style == self.markerstyle {
+            return false;
+        }
+        self.markerstyle = style;
+        let mut changed = false;
+        for section in &mut self.sections {
+            if let Section::Named(..) = section {
+                section.forget_raw();
+                changed = true;
             }
-            Ok(mut file) => match &mut self.metafile {
-                None => {
-                    file.write_all(self.to_string().as_bytes()).unwrap();
+        }
+        changed
+    }
+
+    /// rewrite all markers in their canonical form and move file wide
+    /// comments to the top, content and hashes stay the same
+    /// return false if the file already is formatted
+    pub fn format(&mut self) -> bool {
+        if self.metafile.is_some() {
+            return false;
+        }
+        let before = self.to_string();
+        let newline = line_ending(&before);
+
+        // file wide comments inside a named section are part of its content
+        let named_ranges = self
+            .get_named_sections()
+            .iter()
+            .map(|(data, _)| data.startline..=data.endline)
+            .collect::<Vec<_>>();
+        let mut filecomments = self
+            .specialcomments
+            .iter()
+            .filter(|comment| {
+                comment.section == "all"
+                    && !named_ranges
+                        .iter()
+                        .any(|range| range.contains(&comment.line))
+            })
+            .cloned()
+            .collect::<Vec<Specialcomment>>();
+        filecomments.sort_by_key(|comment| match comment.comment_type {
+            CommentType::SyntaxInfo => 0,
+            CommentType::TargetInfo => 1,
+            _ => 2,
+        });
+        let moved_lines = filecomments
+            .iter()
+            .map(|comment| comment.line)
+            .collect::<HashSet<u32>>();
+
+        let mut header = String::new();
+        for comment in &filecomments {
+            header.push_str(&Specialcomment::new_string(
+                &self.commentsign,
+                comment.comment_type.clone(),
+                "all",
+                comment.argument.as_deref(),
+                &comment.options,
+            ));
+            header.push_str(newline);
+        }
+
+        for section in self.sections.iter_mut() {
+            match section {
+                Section::Named(..) => section.forget_raw(),
+                Section::Anonymous(data) => {
+                    let content = data
+                        .content
+                        .split_inclusive('\n')
+                        .enumerate()
+                        .filter(|(index, _)| {
+                            !moved_lines.contains(&(data.startline + *index as u32))
+                        })
+                        .map(|(_, line)| line)
+                        .collect::<String>();
+                    data.content = content;
                 }
-                Some(metafile) => {
-                    file.write_all(metafile.content.as_bytes()).unwrap();
-                    metafile.write_to_file();
+            }
+        }
+
+        if !header.is_empty() {
+            match self.sections.first_mut() {
+                Some(Section::Anonymous(data)) if data.startline == 1 => {
+                    // hashbangs and xml declarations have to stay on the first line
+                    let mut position = 0;
+                    if data.content.starts_with("#!") || data.content.starts_with("<?xml") {
+                        if !data.content.contains('\n') {
+                            data.content.push_str(newline);
+                        }
+                        position = data.content.find('\n').unwrap() + 1;
+                    }
+                    data.content.insert_str(position, &header);
                 }
-            },
+                _ => {
+                    let mut section = Section::new_anonymous(1, 1);
+                    section.push_str(&header);
+                    self.sections.insert(0, section);
+                }
+            }
         }
 
-        if let Some(permissions) = self.permissions {
-            let mut perms = fs::metadata(targetname).unwrap().permissions();
-            let permint = u32::from_str_radix(&format!("{}", permissions + 1000000), 8).unwrap();
-            perms.set_mode(permint);
-            println!("setting permissions");
-            fs::set_permissions(targetname, perms).expect("failed to set permissions");
+        self.to_string() != before
+    }
+
+    /// replace the file on disk, the old content stays intact if writing fails
+    pub fn write_to_file(&mut self, session: &mut Session) -> io::Result<()> {
+        self.write_from(None, session)
+    }
+
+    /// write_to_file, the journal records the file the change came from
+    pub fn write_from(&mut self, source: Option<&str>, session: &mut Session) -> io::Result<()> {
+        let targetname = expand_tilde(&self.filename);
+        let target = Path::new(&targetname);
+        let before = fs::read(target).ok();
+        let content = self.to_string();
+        let sources = match source {
+            Some(source) => vec![display_path(Path::new(source))],
+            // changes made in place come from the file itself
+            None if self.sources.is_empty() => vec![display_path(target)],
+            None => self.sources.clone(),
+        };
+        let entry = JournalEntry::new(target, before.as_deref(), Some(content.as_bytes()))
+            .with_sources(sources);
+
+        let mode = self.permissions.and_then(permission_mode);
+        let result = session
+            .snapshot(target)
+            .and_then(|_| write_atomic(target, content.as_bytes(), mode));
+        match &result {
+            Ok(()) => session.journal.record(&entry),
+            Err(e) => session.journal.record(&entry.failed(&e.to_string())),
+        }
+        result?;
+        // the metafile is a change of its own in the journal
+        if let Some(metafile) = &mut self.metafile {
+            metafile.write_to_file(session)?;
         }
+        self.store_bases(&session.store);
+        Ok(())
     }
 
-    // create the target file if not existing
-    // TODO: result
-    pub fn create_file(source: &DotFile) -> bool {
-        let targetpath = String::from(source.targetfile.clone().unwrap());
-        let realtargetpath = expand_tilde(&targetpath);
-        // create new file
-        match &source.metafile {
-            None => {
-                let mut targetfile: DotFile = DotFile {
-                    specialcomments: source.specialcomments.clone(),
-                    sections: source.sections.clone(),
-                    filename: realtargetpath.clone(),
-                    targetfile: Option::Some(targetpath),
-                    commentsign: source.commentsign.clone(),
-                    file: source.file.try_clone().unwrap(),
-                    metafile: None,
-                    modified: source.modified,
-                    permissions: source.permissions,
-                };
-                targetfile.write_to_file();
-                return true;
-            }
-            Some(metafile) => {
-                if metafile.modified {
-                    println!(
-                        "{}",
-                        format!("{} modified, skipping", &source.filename).yellow()
-                    );
-                    return false;
-                }
-                OpenOptions::new()
-                    .write(true)
-                    .open(&realtargetpath)
-                    .expect(&format!("cannot open file {}", &targetpath))
-                    .write_all(metafile.content.as_bytes())
-                    .expect(&format!("could not write file {}", &targetpath));
-                let mut newmetafile = MetaFile::from(PathBuf::from(&realtargetpath));
-                newmetafile.sourcefile = Some(source.filename.clone());
-                newmetafile.permissions = metafile.permissions;
-                newmetafile.write_to_file();
-                newmetafile.write_permissions();
-                return true;
+    /// name, content hash and marker hash of every named section,
+    /// nested ones included
+    pub fn section_hashes(&self) -> Vec<(String, String, String)> {
+        self.get_named_sections()
+            .into_iter()
+            .map(|(_, named_data)| {
+                (
+                    named_data.name.clone(),
+                    named_data.hash.clone(),
+                    named_data.targethash.clone(),
+                )
+            })
+            .collect()
+    }
+
+    /// unified diff between the file on disk and what write_to_file would write
+    /// hunks are labeled with the section they change
+    pub fn pending_diff(&mut self) -> String {
+        let filename = expand_tilde(&self.filename);
+        let ondisk = fs::read_to_string(&filename).unwrap_or_default();
+        let mut ret = labeled_diff(
+            &ondisk,
+            &self.to_string(),
+            &filename,
+            &filename,
+            &DotFile::from_content(&filename, &ondisk).line_sections(),
+            &self.line_sections(),
+        );
+        if let Some(metafile) = &mut self.metafile {
+            ret.push_str(&metafile.pending_diff());
+        }
+        ret
+    }
+
+    // name of the top level section every line of the output belongs to
+    fn line_sections(&self) -> Vec<Option<String>> {
+        if self.metafile.is_some() {
+            return Vec::new();
+        }
+        let mut ret = Vec::new();
+        for section in &self.sections {
+            let name = match section {
+                Section::Named(_, named_data) => Some(named_data.name.clone()),
+                Section::Anonymous(_) => None,
+            };
+            let lines = section
+                .output(&self.commentsign, self.markerstyle)
+                .split_inclusive('\n')
+                .count();
+            ret.extend(std::iter::repeat_n(name, lines));
+        }
+        ret
+    }
+
+    // remember the content of unmodified sections, it is the base
+    // of a three way merge once the user changed them, along with the
+    // upstream content of merges. metafiles store their content themselves
+    fn store_bases(&self, store: &ObjectStore) {
+        if let Err(e) = store.add_managed(Path::new(&expand_tilde(&self.filename))) {
+            eprintln!("could not remember {} for gc: {}", self.filename, e);
+        }
+        let unmodified = self
+            .get_named_sections()
+            .into_iter()
+            .filter(|(_, named_data)| !named_data.is_modified())
+            .map(|(data, _)| &data.content);
+        for content in unmodified.chain(self.bases.iter()) {
+            if let Err(e) = store.put(content) {
+                eprintln!("could not store content of {}: {}", self.filename, e);
+                return;
             }
         }
     }
 
+    // the sections of a file as a new file at another path
+    fn copy_to(&self, path: &str) -> DotFile {
+        DotFile {
+            specialcomments: self.specialcomments.clone(),
+            sections: self.sections.clone(),
+            filename: String::from(path),
+            targetfile: self.targetfile.clone(),
+            commentsign: self.commentsign.clone(),
+            language: self.language.clone(),
+            metafile: None,
+            modified: self.modified,
+            permissions: self.permissions,
+            bom: self.bom,
+            markerstyle: self.markerstyle,
+            diagnostics: Vec::new(),
+            bases: self.bases.clone(),
+            sources: Vec::new(),
+        }
+    }
+
     pub fn is_anonymous(&self) -> bool {
-        self.count_named_sections() > 0
+        self.count_named_sections() == 0
     }
 
-    pub fn apply(&self) -> ApplyResult {
-        let mut donesomething = false;
-        if let Some(target) = &self.targetfile {
-            if create_file(&target) {
-                if DotFile::create_file(self) {
+    /// apply the file to its target, a dry run prints the changes instead
+    pub fn apply(
+        &self,
+        conflicts: ConflictStyle,
+        dry_run: bool,
+        session: &mut Session,
+    ) -> ApplyResult {
+        let plan = match self.plan_apply(conflicts, session) {
+            Ok(plan) => plan,
+            Err(e) => {
+                eprintln!("{}", e.red());
+                return ApplyResult::Error;
+            }
+        };
+        if !dry_run {
+            return self.commit_apply(plan, session);
+        }
+        let (ApplyPlan::Create(mut targetfile) | ApplyPlan::Update(mut targetfile)) = plan else {
+            return ApplyResult::Unchanged;
+        };
+        let diff = targetfile.pending_diff();
+        print!("{}", diff);
+        if diff.is_empty() {
+            ApplyResult::Unchanged
+        } else {
+            ApplyResult::Changed
+        }
+    }
+
+    /// what apply would do to the target, nothing is written
+    pub fn plan_apply(
+        &self,
+        conflicts: ConflictStyle,
+        session: &Session,
+    ) -> Result<ApplyPlan, String> {
+        let Some(target) = &self.targetfile else {
+            return Err(format!("{} has no target file", self.filename));
+        };
+        let target = expand_tilde(target);
+        if !Path::new(&target).is_file() {
+            // a new target starts out as a copy of the source
+            return Ok(match &self.metafile {
+                Some(metafile) if metafile.modified => {
                     println!(
-                        "applied {} to create {} ",
-                        &self.filename.green(),
-                        &target.bold()
+                        "{}",
+                        format!("{} modified, skipping", self.filename).yellow()
                     );
-                    donesomething = true;
+                    ApplyPlan::Unchanged
                 }
-            } else {
-                let mut targetfile = match DotFile::new(&expand_tilde(&target)) {
-                    Ok(file) => file,
-                    Err(_) => {
-                        eprintln!("failed to parse {}", &target.red());
-                        return ApplyResult::Error;
-                    }
-                };
-                if targetfile.applyfile(&self) {
-                    println!("applied {} to {} ", &self.filename.green(), &target.bold());
-                    targetfile.write_to_file();
-                    donesomething = true;
+                Some(metafile) => {
+                    let mut newmetafile =
+                        MetaFile::from_content(Path::new(&target), &metafile.content);
+                    newmetafile.sourcefile = Some(self.filename.clone());
+                    newmetafile.permissions = metafile.permissions;
+                    ApplyPlan::Create(DotFile {
+                        filename: target.clone(),
+                        targetfile: None,
+                        permissions: metafile.permissions,
+                        metafile: Some(newmetafile),
+                        ..DotFile::from_content(&target, "")
+                    })
                 }
-            }
-        } else {
-            println!("{} has no target file", &self.filename.red());
-            return ApplyResult::Error;
+                None => ApplyPlan::Create(self.copy_to(&target)),
+            });
         }
-        if donesomething {
-            return ApplyResult::Changed;
+        let mut targetfile =
+            DotFile::new(&target).map_err(|e| format!("failed to parse {}: {}", target, e))?;
+        if targetfile.applyfile(self, conflicts, session) {
+            Ok(ApplyPlan::Update(targetfile))
         } else {
-            return ApplyResult::Unchanged;
+            Ok(ApplyPlan::Unchanged)
+        }
+    }
+
+    /// write what plan_apply worked out
+    pub fn commit_apply(&self, plan: ApplyPlan, session: &mut Session) -> ApplyResult {
+        let Some(target) = &self.targetfile else {
+            return ApplyResult::Error;
+        };
+        match plan {
+            ApplyPlan::Unchanged => ApplyResult::Unchanged,
+            ApplyPlan::Create(mut targetfile) => {
+                if let Err(e) = create_parent(target) {
+                    eprintln!("could not create directory of {}: {}", target.red(), e);
+                    return ApplyResult::Error;
+                }
+                if let Err(e) = targetfile.write_from(Some(&self.filename), session) {
+                    eprintln!("could not write {}: {}", target.red(), e);
+                    return ApplyResult::Error;
+                }
+                println!(
+                    "applied {} to create {} ",
+                    &self.filename.green(),
+                    &target.bold()
+                );
+                ApplyResult::Changed
+            }
+            ApplyPlan::Update(mut targetfile) => {
+                if let Err(e) = targetfile.write_from(Some(&self.filename), session) {
+                    eprintln!("could not write {}: {}", target.red(), e);
+                    return ApplyResult::Error;
+                }
+                println!("applied {} to {} ", &self.filename.green(), &target.bold());
+                ApplyResult::Changed
+            }
         }
     }
 
@@ -539,7 +1216,7 @@ impl DotFile {
                 return false;
             } else {
                 if other.is_anonymous() {
-                    eprintln!("{} {}", other.filename.red(), "is unmanaged, cannot apply");
+                    eprintln!("{} is unmanaged, cannot apply", other.filename.red());
                     return false;
                 } else {
                     return true;
@@ -572,7 +1249,12 @@ impl DotFile {
     // return true if file will be modified
     // applies other file to self
     // TODO: return result
-    pub fn applyfile(&mut self, inputfile: &DotFile) -> bool {
+    pub fn applyfile(
+        &mut self,
+        inputfile: &DotFile,
+        conflicts: ConflictStyle,
+        session: &Session,
+    ) -> bool {
         if !self.can_apply(inputfile) {
             return false;
         }
@@ -582,7 +1264,7 @@ impl DotFile {
                 let mut modified = false;
 
                 // true if input file contains all sections that self has
-                let mut allsections = self.has_same_sections(&inputfile);
+                let allsections = self.has_same_sections(inputfile);
 
                 if !self.modified && allsections {
                     // copy entire file contents if all sections are unmodified
@@ -597,7 +1279,12 @@ impl DotFile {
                 } else {
                     let mut applycounter = 0;
                     for (data, named_data) in inputfile.get_named_sections() {
-                        if self.applysection(data.clone(), named_data.clone()) {
+                        if self.applysection(
+                            data.clone(),
+                            named_data.clone(),
+                            conflicts,
+                            &session.store,
+                        ) {
                             applycounter += 1;
                             modified = true;
                         }
@@ -627,39 +1314,51 @@ impl DotFile {
 
             // apply entire content if file is managed by metafile
             Some(metafile) => {
-                if !metafile.modified {
-                    if let Some(applymetafile) = &inputfile.metafile {
-                        if applymetafile.modified {
-                            println!("source file {} modified", &applymetafile.parentfile);
-                            return false;
-                        }
-                        if metafile.hash == applymetafile.hash {
-                            println!("file {} already up to date", self.filename.bold());
-                            return false;
-                        }
-                        metafile.content = applymetafile.content.clone();
-                        metafile.hash = applymetafile.hash.clone();
-
-                        println!(
-                            "applied {} to {}",
-                            inputfile.filename.bold(),
-                            self.filename.bold()
-                        );
-                        return true;
+                let Some(applymetafile) = &inputfile.metafile else {
+                    return false;
+                };
+                if applymetafile.modified {
+                    println!("source file {} modified", &applymetafile.parentfile);
+                    return false;
+                }
+                if metafile.hash == applymetafile.hash {
+                    println!("file {} already up to date", self.filename.bold());
+                    return false;
+                }
+                if metafile.modified {
+                    if !merge_metafile(
+                        &self.filename,
+                        metafile,
+                        &applymetafile.content,
+                        conflicts,
+                        &session.store,
+                    ) {
+                        return false;
                     }
+                    self.bases.push(applymetafile.content.clone());
                 } else {
-                    println!(
-                        "{}",
-                        format!("target {} modified, skipping", &self.filename.bold()).yellow()
-                    );
+                    metafile.content = applymetafile.content.clone();
+                    metafile.hash = applymetafile.hash.clone();
                 }
-                return false;
+
+                println!(
+                    "applied {} to {}",
+                    inputfile.filename.bold(),
+                    self.filename.bold()
+                );
+                return true;
             }
         }
     }
 
-    fn applysection(&mut self, sectiondata: SectionData, named_data: NamedSectionData) -> bool {
-        if let Some(_) = &self.metafile {
+    fn applysection(
+        &mut self,
+        sectiondata: SectionData,
+        named_data: NamedSectionData,
+        conflicts: ConflictStyle,
+        store: &ObjectStore,
+    ) -> bool {
+        if self.metafile.is_some() {
             eprintln!(
                 "{}",
                 "cannot apply individual section to file managed by metafile"
@@ -668,190 +1367,439 @@ impl DotFile {
             );
             return false;
         }
-        if named_data.hash != named_data.targethash {
+        if named_data.is_modified() {
             eprintln!("cannot apply modified section");
             return false;
         }
 
-        for section_index in 0..self.sections.len() {
-            let tmpsection = self.sections.get(section_index).unwrap();
-            if let Section::Named(src_data, src_named_data) = tmpsection {
-                if src_named_data.name.eq(&named_data.name) {
-                    self.sections[section_index] = Section::Named(sectiondata, named_data);
-                    return true;
+        let name = named_data.name.clone();
+        let mut newsection = Section::Named(sectiondata, named_data);
+        for section in self.sections.iter_mut() {
+            let Some(oldsection) = section.find(&name) else {
+                continue;
+            };
+            // changes of the user are merged with upstream, or left alone
+            if let Section::Named(old_data, old_named) = oldsection {
+                if old_named.is_modified() {
+                    let Section::Named(new_data, new_named) = &mut newsection else {
+                        return false;
+                    };
+                    if !old_named.children.is_empty() || !new_named.children.is_empty() {
+                        eprintln!(
+                            "{} {}",
+                            "cannot merge modified section with nested sections".yellow(),
+                            name.bold()
+                        );
+                        return false;
+                    }
+                    let Some(merged) = merge_content(
+                        &format!("section {} of {}", name, self.filename),
+                        &old_named.targethash,
+                        &old_data.content,
+                        &new_data.content,
+                        conflicts,
+                        store,
+                    ) else {
+                        return false;
+                    };
+                    // the target hash stays the one of upstream, so the changes
+                    // of the user still show up as modifications on top of it
+                    // upstream is the base of the next merge
+                    self.bases
+                        .push(std::mem::replace(&mut new_data.content, merged));
+                    new_data.indented.clear();
+                    new_named.raw = None;
+                    newsection.finalize();
+                }
+            }
+            // nested sections the user modified are kept
+            let kept = oldsection
+                .named_sections()
+                .into_iter()
+                .skip(1)
+                .filter(|(_, nested)| nested.is_modified())
+                .filter_map(|(_, nested)| oldsection.find(&nested.name).cloned())
+                .collect::<Vec<Section>>();
+            // keep the indentation of the target, content is relative to it
+            let indent = oldsection.get_data().indent.clone();
+
+            for nested in kept {
+                let Section::Named(_, nested_data) = &nested else {
+                    continue;
+                };
+                let nestedname = nested_data.name.clone();
+                // upstream removed or renamed it, applying would lose the changes
+                if !newsection.replace_nested(nested) {
+                    eprintln!(
+                        "{} {}, modified section {} is not part of it anymore",
+                        "cannot apply".red().bold(),
+                        name.bold(),
+                        nestedname.yellow()
+                    );
+                    return false;
                 }
+                eprintln!("keeping modified section {}", nestedname.yellow());
+            }
+            newsection.set_indent(&indent);
+            if matches!(section, Section::Named(_, old_named) if old_named.name == name) {
+                *section = newsection;
+            } else {
+                section.replace_nested(newsection);
             }
+            return true;
         }
         return false;
     }
+}
 
-    pub fn get_hashbang(&self) -> Option<String> {
-        let firstsection = self.sections.get(0).unwrap();
-        if let Section::Anonymous(section_data) = firstsection {
-            let firstline = section_data.content.split("\n").nth(0).unwrap();
-            if Regex::new("^#!/.*").unwrap().is_match(&firstline) {
-                return Some(String::from(firstline));
+impl fmt::Display for DotFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.metafile {
+            None => {
+                if self.bom {
+                    write!(f, "{}", BOM)?;
+                }
+                for i in &self.sections {
+                    write!(f, "{}", i.output(&self.commentsign, self.markerstyle))?;
+                }
+                Ok(())
             }
+            Some(metafile) => write!(f, "{}", metafile.content),
         }
-        None
-    }
-
-    fn get_property_comments(&self) -> String {
-        let mut retstr = String::new();
-        // TODO: do same thing with all "all" section comments
-        if let Some(targetfile) = &self.targetfile {
-            retstr.push_str(&Specialcomment::new_string(
-                &self.commentsign,
-                CommentType::TargetInfo,
-                "all",
-                None,
-            ));
-            retstr.push_str("\n");
-        }
-
-        retstr
     }
 }
 
-impl ToString for DotFile {
-    fn to_string(&self) -> String {
-        match &self.metafile {
-            None => {
-                let mut retstr = String::new();
-                let outputsections;
-
-                // respect hashbang
-                // and put comments below it
-                match self.get_hashbang() {
-                    Some(hashbang) => {
-                        retstr.push_str(&format!("{}\n", hashbang));
-                        retstr.push_str(&self.get_property_comments());
-                        retstr.push_str(
-                            &self
-                                .sections
-                                .get(0)
-                                .unwrap()
-                                .get_data()
-                                .content
-                                .lines()
-                                .collect::<Vec<&str>>()[1..]
-                                .join("\n"),
-                        );
-                        outputsections = &self.sections[1..];
-                    }
-                    None => {
-                        retstr.push_str(&self.get_property_comments());
-                        outputsections = &self.sections[..];
-                    }
-                }
+const BOM: char = '\u{feff}';
+
+// split the lines start..=end into named sections and the anonymous text between them
+// named has to be sorted by line and contain every section in the range, nested ones too
+fn fill_sections(
+    named: &[Section],
+    start: u32,
+    end: u32,
+    indent: &str,
+    lines: &[ContentLine],
+    marker_lines: &HashSet<u32>,
+) -> Vec<Section> {
+    let mut ret = Vec::new();
+    let mut currentline = start;
+    let mut index = 0;
+    while index < named.len() {
+        let (startline, endline) = (
+            named[index].get_data().startline,
+            named[index].get_data().endline,
+        );
+        // nested sections directly follow their parent
+        let nested = named[index + 1..]
+            .iter()
+            .take_while(|section| section.get_data().startline < endline)
+            .count();
+        let children = &named[index + 1..index + 1 + nested];
+
+        ret.extend(anonymous_section(
+            currentline,
+            startline - 1,
+            indent,
+            lines,
+            marker_lines,
+        ));
 
-                for i in outputsections {
-                    retstr.push_str(&i.output(&self.commentsign));
+        let mut section = named[index].clone();
+        let sectionlines = &lines[(startline - 1) as usize..endline as usize];
+        if children.is_empty() {
+            for line in sectionlines {
+                if !marker_lines.contains(&line.linenumber) {
+                    section.push_str(&line.content);
                 }
-                return retstr;
             }
-            Some(metafile) => {
-                return metafile.content.clone();
+        } else {
+            let sectionindent = section.get_data().indent.clone();
+            let filled = fill_sections(
+                children,
+                startline,
+                endline,
+                &sectionindent,
+                lines,
+                marker_lines,
+            );
+            if let Section::Named(_, named_data) = &mut section {
+                named_data.children = filled;
             }
         }
+        section.finalize();
+        if let Section::Named(_, named_data) = &mut section {
+            named_data.raw = Some(
+                sectionlines
+                    .iter()
+                    .map(|line| line.content.as_str())
+                    .collect(),
+            );
+        }
+        ret.push(section);
+
+        currentline = endline + 1;
+        index += 1 + nested;
     }
+    ret.extend(anonymous_section(
+        currentline,
+        end,
+        indent,
+        lines,
+        marker_lines,
+    ));
+    ret
 }
 
-// detect comment syntax for file based on filename, extension and hashbang
-fn get_comment_sign(filename: &str, firstline: &str) -> String {
-    let fpath = Path::new(filename);
+// text between named sections, relative to the indentation of the surrounding section
+fn anonymous_section(
+    start: u32,
+    end: u32,
+    indent: &str,
+    lines: &[ContentLine],
+    marker_lines: &HashSet<u32>,
+) -> Option<Section> {
+    if start > end {
+        return None;
+    }
+    let mut section = Section::new_anonymous(start, end);
+    for line in &lines[(start - 1) as usize..end as usize] {
+        if !marker_lines.contains(&line.linenumber) {
+            section.push_nested(&line.content, indent);
+        }
+    }
+    if section.get_data().content.is_empty() {
+        return None;
+    }
+    Some(section)
+}
 
-    let file_name_commentsigns: HashMap<&str, &str> = HashMap::from([
-        ("dunstrc", "#"),
-        ("jgmenurc", "#"),
-        ("zshrc", "#"),
-        ("bashrc", "#"),
-        ("Xresources", "!"),
-        ("xsettingsd", "#"),
-        ("vimrc", "\""),
-    ]);
+// remove \n or \r\n from the end of a line
+fn strip_line_ending(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
 
-    // get comment syntax via file name
-    let fname = fpath.file_name().and_then(OsStr::to_str);
-    match fname {
-        Some(name) => {
-            let filename = String::from(String::from(name).trim_start_matches("."));
-            match file_name_commentsigns.get(filename.as_str()) {
-                Some(sign) => {
-                    return String::from(sign.deref());
-                }
-                None => {}
-            }
+// output of a cmd: source, None if it may not run or failed
+// problems are reported as diagnostics on the given line
+fn command_source(
+    filename: &str,
+    command: &[String],
+    timeout: Duration,
+    line: u32,
+    options: &UpdateOptions,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<String> {
+    let commandline = SourceUri {
+        location: SourceLocation::Command(command.to_vec()),
+        section: None,
+    }
+    .to_string();
+    if options.dry_run {
+        eprintln!(
+            "{} {}, commands are not run in dry runs",
+            "not previewed".yellow(),
+            commandline.bold()
+        );
+        return None;
+    }
+    if !options.commands.allows(&command[0]) {
+        diagnostics.push(Diagnostic::error(
+            "command-not-allowed",
+            line,
+            &format!(
+                "{} is not in the allowed commands of commands.toml",
+                command[0]
+            ),
+        ));
+        return None;
+    }
+    if !options.trust && !options.commands.trusts(Path::new(filename)) {
+        if !io::stdin().is_terminal() {
+            eprintln!(
+                "{} {} from untrusted file {}, use --trust to run it",
+                "not running".yellow(),
+                commandline.bold(),
+                filename
+            );
+            return None;
+        }
+        eprint!(
+            "{} wants to run {}, allow? [y/N] ",
+            filename,
+            commandline.bold()
+        );
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err()
+            || !matches!(answer.trim(), "y" | "Y" | "yes")
+        {
+            return None;
         }
-        None => {}
     }
 
-    let mut file_type_commentsigns: HashMap<&str, &str> = HashMap::from([
-        ("py", "#"),
-        ("sh", "#"),
-        ("zsh", "#"),
-        ("bash", "#"),
-        ("fish", "#"),
-        ("c", "//"),
-        ("cpp", "//"),
-        ("rasi", "//"),
-        ("desktop", "#"),
-        ("conf", "#"),
-        ("vim", "\""),
-        ("reg", ";"),
-        ("rc", "#"),
-        ("ini", ";"),
-        ("xresources", "!"),
-    ]);
-
-    let ext = fpath.extension().and_then(OsStr::to_str);
+    let output = match command::run(command, timeout) {
+        Ok(output) => output,
+        Err(e) => {
+            diagnostics.push(Diagnostic::error(
+                "command-failed",
+                line,
+                &format!("{} failed: {}", commandline, e),
+            ));
+            return None;
+        }
+    };
+    let stderr = output.stderr.trim_end();
+    if !stderr.is_empty() {
+        diagnostics.push(Diagnostic::warning(
+            "command-stderr",
+            line,
+            &format!("{} wrote to stderr: {}", commandline, stderr),
+        ));
+    }
+    if !output.status.success() {
+        diagnostics.push(Diagnostic::error(
+            "command-failed",
+            line,
+            &format!("{} failed with {}", commandline, output.status),
+        ));
+        return None;
+    }
+    // the last line has to end like every other line, or the hash changes once written
+    let mut text = output.stdout;
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    Some(text)
+}
 
-    // get comment syntax via file extension
-    match ext {
-        Some(extension) => {
-            let tester = file_type_commentsigns.get(extension);
-            match tester {
-                Some(sign) => {
-                    return String::from(sign.deref());
-                }
-                None => {}
-            }
+// three way merge of changes of the user with new upstream content,
+// based on the content stored when it was last compiled
+// None if there is no base or there are conflicts that should not be written
+fn merge_content(
+    name: &str,
+    basehash: &str,
+    local: &str,
+    upstream: &str,
+    conflicts: ConflictStyle,
+    store: &ObjectStore,
+) -> Option<String> {
+    let Some(base) = store.get(basehash) else {
+        eprintln!(
+            "{} {}, its original content is unknown",
+            "cannot merge modified".yellow(),
+            name.bold()
+        );
+        return None;
+    };
+    let merged = merge(&base, local, upstream);
+    if merged.conflicts > 0 {
+        if conflicts == ConflictStyle::Report {
+            eprintln!(
+                "{} {} conflicts merging {}, use --conflict-markers to apply them anyway",
+                "not applied:".red().bold(),
+                merged.conflicts,
+                name
+            );
+            return None;
         }
-        None => {}
+        eprintln!(
+            "{} {} conflicts in {}",
+            "marked".yellow().bold(),
+            merged.conflicts,
+            name
+        );
+    } else {
+        eprintln!("merged changes of {}", name.green());
     }
+    Some(merged.content)
+}
 
-    // get comment syntax via #!/hashbang
+// merge a file managed by a metafile the user changed
+fn merge_metafile(
+    filename: &str,
+    metafile: &mut MetaFile,
+    upstream: &str,
+    conflicts: ConflictStyle,
+    store: &ObjectStore,
+) -> bool {
+    let Some(merged) = merge_content(
+        filename,
+        &metafile.hash,
+        &metafile.content,
+        upstream,
+        conflicts,
+        store,
+    ) else {
+        return false;
+    };
+    metafile.content = merged;
+    metafile.hash = digest(upstream).to_uppercase();
+    metafile.finalize();
+    true
+}
 
-    let mut file_hashbang_commentsigns: HashMap<&str, &str> = HashMap::from([
-        ("python", "#"),
-        ("sh", "#"),
-        ("bash", "#"),
-        ("zsh", "#"),
-        ("fish", "#"),
-        ("node", "//"),
-    ]);
+// source files read so far, by location
+type SourceCache = HashMap<SourceLocation, Result<(DotFile, Option<String>), String>>;
+
+// the section a source points to and the commit it was read from
+// every source file is only read once
+fn upstream_section(
+    uri: &SourceUri,
+    name: &str,
+    sourcefiles: &mut SourceCache,
+    offline: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Result<(SectionData, NamedSectionData, Option<String>), String> {
+    let (sourcefile, revision) = sourcefiles
+        .entry(uri.location.clone())
+        .or_insert_with(|| load_source(uri, offline, dry_run, session))
+        .as_ref()
+        .map_err(|e| format!("could not read source {}: {}", uri, e))?;
+    let sectionname = uri.section_name(name);
+    match sourcefile.get_section(sectionname) {
+        Some(Section::Named(data, named_data)) => {
+            Ok((data.clone(), named_data.clone(), revision.clone()))
+        }
+        _ => Err(format!("source {} has no section {}", uri, sectionname)),
+    }
+}
 
-    match Regex::new("^#!/.*[/ ](.*)$").unwrap().captures(&firstline) {
-        Some(captures) => {
-            let application = captures.get(1).unwrap().as_str();
-            match file_hashbang_commentsigns.get(application) {
-                Some(sign) => {
-                    return String::from(sign.deref());
-                }
-                None => {}
-            }
+// read and parse the file a section source points to, files on disk
+// are opened directly so metafiles next to them are found
+// returns the commit the content was taken from for git sources
+fn load_source(
+    uri: &SourceUri,
+    offline: bool,
+    dry_run: bool,
+    session: &Session,
+) -> Result<(DotFile, Option<String>), String> {
+    match &uri.location {
+        SourceLocation::File(path) => DotFile::from_pathbuf(path)
+            .map(|file| (file, None))
+            .map_err(|e| e.to_string()),
+        SourceLocation::Git { .. } | SourceLocation::Http(_) | SourceLocation::Command(_) => {
+            let content = uri.read(offline, dry_run, session)?;
+            Ok((
+                DotFile::from_content(&uri.filename(), &content.text),
+                content.revision,
+            ))
         }
-        None => {}
     }
+}
 
-    return String::from("#");
+// directory relative sources of a file are resolved against
+fn source_base(filename: &str) -> PathBuf {
+    Path::new(filename)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("/"))
 }
 
 // expand tilde in path into the home folder
 pub fn expand_tilde(input: &str) -> String {
     let mut retstr = String::from(input);
     if retstr.starts_with("~/") {
-        retstr = String::from(format!(
+        retstr = format!(
             "{}/{}",
             home::home_dir()
                 .unwrap()
@@ -859,30 +1807,17 @@ pub fn expand_tilde(input: &str) -> String {
                 .into_string()
                 .unwrap(),
             retstr.strip_prefix("~/").unwrap()
-        ));
+        );
     }
     return retstr;
 }
 
-// create file with directory creation and
+// create the directory a file goes into, with
 // parsing of the home tilde
 // MAYBETODO: support environment variables
-// return false if file already exists
-pub fn create_file(path: &str) -> bool {
-    let realtargetname = expand_tilde(path);
-
-    let checkpath = Path::new(&realtargetname);
-    if !checkpath.is_file() {
-        let bufpath = checkpath.to_path_buf();
-        match bufpath.parent() {
-            Some(parent) => {
-                std::fs::create_dir_all(parent.to_str().unwrap()).unwrap();
-            }
-            None => {}
-        }
-        File::create(&realtargetname).unwrap();
-        return true;
-    } else {
-        return false;
+fn create_parent(path: &str) -> io::Result<()> {
+    match Path::new(&expand_tilde(path)).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(()),
     }
 }
@@ -0,0 +1,310 @@
+use std::fs::{self, read_to_string, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use serde_json::{json, Value};
+use sha256::digest;
+
+use crate::files::DotFile;
+use crate::timestamp;
+
+/// a section whose content a write changed
+#[derive(Clone, Debug, PartialEq)]
+pub struct SectionChange {
+    pub name: String,
+    pub before: Option<String>, // None if the section was added
+    pub after: Option<String>,  // None if the section was removed
+}
+
+/// what imosid did to one file
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    pub time: u64,
+    pub command: String,
+    pub target: String,
+    pub sources: Vec<String>, // files or source uris the new content came from
+    pub before: Option<String>, // hash of the whole file, None if it did not exist
+    pub after: Option<String>, // None if the file was removed
+    pub sections: Vec<SectionChange>,
+    pub result: String,
+    pub message: Option<String>, // what went wrong
+}
+
+impl JournalEntry {
+    /// a change of the target from the content before to the content after
+    pub fn new(target: &Path, before: Option<&[u8]>, after: Option<&[u8]>) -> JournalEntry {
+        let filehash =
+            |content: Option<&[u8]>| content.map(|content| digest(content).to_uppercase());
+        // compare sections of text files, metafiles and binary files have none
+        let sections = |content: Option<&[u8]>| {
+            content
+                .and_then(|content| std::str::from_utf8(content).ok())
+                .map(|content| {
+                    DotFile::from_content(&target.to_string_lossy(), content).section_hashes()
+                })
+                .unwrap_or_default()
+        };
+        let (old, new) = (sections(before), sections(after));
+        let find = |sections: &[(String, String, String)], name: &str| {
+            sections
+                .iter()
+                .find(|(section, _, _)| section == name)
+                .map(|(_, hash, targethash)| (hash.clone(), targethash.clone()))
+        };
+        // compiling only changes the hash in the markers, which touches the section too
+        let mut changes: Vec<SectionChange> = Vec::new();
+        for (name, _, _) in old.iter().chain(new.iter()) {
+            let (before, after) = (find(&old, name), find(&new, name));
+            if before == after || changes.iter().any(|change| &change.name == name) {
+                continue;
+            }
+            changes.push(SectionChange {
+                name: name.clone(),
+                before: before.map(|(hash, _)| hash),
+                after: after.map(|(hash, _)| hash),
+            });
+        }
+
+        JournalEntry {
+            time: timestamp::now(),
+            command: String::new(), // filled in by the journal of the run
+            target: display_path(target),
+            sources: Vec::new(),
+            before: filehash(before),
+            after: filehash(after),
+            sections: changes,
+            result: String::from("changed"),
+            message: None,
+        }
+    }
+
+    /// the entry with the file the content came from
+    pub fn with_source(self, source: &Path) -> JournalEntry {
+        self.with_sources(vec![display_path(source)])
+    }
+
+    pub fn with_sources(mut self, sources: Vec<String>) -> JournalEntry {
+        self.sources = sources;
+        self
+    }
+
+    /// the entry for a write that did not happen
+    pub fn failed(mut self, error: &str) -> JournalEntry {
+        self.result = String::from("failed");
+        self.after = self.before.clone();
+        self.sections.clear();
+        self.message = Some(String::from(error));
+        self
+    }
+
+    fn json(&self) -> Value {
+        let sections = self
+            .sections
+            .iter()
+            .map(|change| {
+                json!({"name": change.name, "before": change.before, "after": change.after})
+            })
+            .collect::<Vec<Value>>();
+        json!({
+            "time": self.time,
+            "command": self.command,
+            "target": self.target,
+            "sources": self.sources,
+            "before": self.before,
+            "after": self.after,
+            "sections": sections,
+            "result": self.result,
+            "message": self.message,
+        })
+    }
+
+    fn from_json(record: &Value) -> Option<JournalEntry> {
+        let string =
+            |value: &Value, key: &str| value.get(key).and_then(Value::as_str).map(String::from);
+        let sections = match record.get("sections") {
+            Some(Value::Array(sections)) => sections
+                .iter()
+                .filter_map(|section| {
+                    Some(SectionChange {
+                        name: string(section, "name")?,
+                        before: string(section, "before"),
+                        after: string(section, "after"),
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(JournalEntry {
+            time: record.get("time")?.as_u64()?,
+            command: string(record, "command").unwrap_or_default(),
+            target: string(record, "target")?,
+            sources: match record.get("sources") {
+                Some(Value::Array(sources)) => sources
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect(),
+                _ => Vec::new(),
+            },
+            before: string(record, "before"),
+            after: string(record, "after"),
+            sections,
+            result: string(record, "result").unwrap_or_default(),
+            message: string(record, "message"),
+        })
+    }
+
+    pub fn pretty(&self) -> String {
+        let short = |hash: &Option<String>| match hash {
+            Some(hash) => hash.chars().take(12).collect(),
+            None => String::from("none"),
+        };
+        let result = match self.result.as_str() {
+            "changed" => self.result.green(),
+            _ => self.result.red(),
+        };
+        let mut ret = format!(
+            "{}  {}  {}  {}\n",
+            timestamp::format_time(self.time),
+            self.command.bold(),
+            result,
+            self.target.bold()
+        );
+        for source in &self.sources {
+            ret.push_str(&format!("    from {}\n", source));
+        }
+        if let Some(message) = &self.message {
+            ret.push_str(&format!("    {}\n", message.red()));
+        }
+        ret.push_str(&format!(
+            "    file {} -> {}\n",
+            short(&self.before),
+            short(&self.after)
+        ));
+        for change in &self.sections {
+            ret.push_str(&format!(
+                "    section {} {} -> {}\n",
+                change.name.bold(),
+                short(&change.before),
+                short(&change.after)
+            ));
+        }
+        ret
+    }
+}
+
+/// which entries imosid log shows
+#[derive(Default)]
+pub struct LogFilter {
+    pub file: Option<PathBuf>, // target or source of the entry
+    pub section: Option<String>,
+    pub since: Option<u64>,
+}
+
+impl LogFilter {
+    pub fn matches(&self, entry: &JournalEntry) -> bool {
+        if let Some(file) = &self.file {
+            let file = display_path(file);
+            // sources may name a section of the file
+            let from_file = |source: &String| {
+                source == &file
+                    || source
+                        .strip_prefix(&file)
+                        .is_some_and(|rest| rest.starts_with('#'))
+            };
+            if entry.target != file && !entry.sources.iter().any(from_file) {
+                return false;
+            }
+        }
+        if let Some(section) = &self.section {
+            if !entry.sections.iter().any(|change| &change.name == section) {
+                return false;
+            }
+        }
+        self.since.is_none_or(|since| entry.time >= since)
+    }
+}
+
+/// append only record of every file imosid wrote
+pub struct Journal {
+    path: PathBuf,
+    pub command: String, // the subcommand imosid is running
+}
+
+impl Journal {
+    pub fn at(path: PathBuf) -> Journal {
+        Journal {
+            path,
+            command: String::new(),
+        }
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // one json record per line, written at once, so an interrupted
+        // append can only damage its own entry
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        // start a new line after the remains of a torn append
+        let mut last = [b'\n'];
+        if file.metadata()?.len() > 0 {
+            file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+        }
+        let separator = if last[0] == b'\n' { "" } else { "\n" };
+        let mut record = entry.json();
+        record["command"] = Value::from(self.command.as_str());
+        file.write_all(format!("{}{}\n", separator, record).as_bytes())
+    }
+
+    /// append, problems with the journal are reported but do not stop imosid
+    pub fn record(&self, entry: &JournalEntry) {
+        if let Err(e) = self.append(entry) {
+            eprintln!("could not write to the journal: {}", e);
+        }
+    }
+
+    /// all entries, oldest first
+    /// damaged entries are skipped with a warning
+    pub fn entries(&self) -> Result<Vec<JournalEntry>, String> {
+        let content = match read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut entries = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Value>(line)
+                .ok()
+                .as_ref()
+                .and_then(JournalEntry::from_json)
+            {
+                Some(entry) => entries.push(entry),
+                None => eprintln!(
+                    "{} skipping damaged entry on line {} of {}",
+                    "warning:".yellow().bold(),
+                    number + 1,
+                    self.path.display()
+                ),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+// paths are recorded absolute, to find them from any directory
+pub fn display_path(path: &Path) -> String {
+    std::path::absolute(path)
+        .unwrap_or(path.to_path_buf())
+        .to_string_lossy()
+        .to_string()
+}
@@ -0,0 +1,489 @@
+use crate::comment::CommentSyntax;
+use colored::Colorize;
+use regex::Regex;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use toml::Value;
+
+// name, comment syntax, file names, globs, extensions, interpreters
+type BuiltinLanguage = (
+    &'static str,
+    &'static str,
+    &'static [&'static str],
+    &'static [&'static str],
+    &'static [&'static str],
+    &'static [&'static str],
+);
+
+// block comment syntax is written as start and end separated by a space
+const BUILTIN_LANGUAGES: &[BuiltinLanguage] = &[
+    (
+        "shell",
+        "#",
+        &["zshrc", "bashrc", "profile", "zprofile", "bash_profile"],
+        &[],
+        &["sh", "zsh", "bash", "fish"],
+        &["sh", "bash", "zsh", "fish", "dash"],
+    ),
+    ("python", "#", &[], &[], &["py"], &["python", "python3"]),
+    (
+        "conf",
+        "#",
+        &["dunstrc", "jgmenurc", "xsettingsd"],
+        &[],
+        &["desktop", "conf", "rc", "toml", "yml", "yaml"],
+        &[],
+    ),
+    (
+        "xresources",
+        "!",
+        &["Xresources"],
+        &[],
+        &["xresources"],
+        &[],
+    ),
+    ("vim", "\"", &["vimrc", "gvimrc"], &[], &["vim"], &[]),
+    ("ini", ";", &[], &[], &["ini", "reg"], &[]),
+    ("c", "//", &[], &[], &["c", "h", "cpp", "hpp", "rs"], &[]),
+    ("rasi", "//", &[], &[], &["rasi"], &[]),
+    ("javascript", "//", &[], &[], &["js"], &["node"]),
+    ("css", "/* */", &[], &[], &["css"], &[]),
+    (
+        "xml",
+        "<!-- -->",
+        &["fonts.conf"],
+        &[],
+        &["xml", "svg", "html", "htm", "xhtml"],
+        &[],
+    ),
+    ("lua", "--", &[], &[], &["lua"], &["lua", "luajit"]),
+    ("sql", "--", &[], &[], &["sql"], &[]),
+    ("tex", "%", &[], &[], &["tex", "sty", "cls", "bib"], &[]),
+    (
+        "lisp",
+        ";",
+        &["emacs"],
+        &[],
+        &["el", "lisp", "scm", "clj"],
+        &["sbcl", "guile", "clisp"],
+    ),
+    (
+        "haskell",
+        "--",
+        &["xmobarrc"],
+        &[],
+        &["hs"],
+        &["runhaskell", "runghc"],
+    ),
+];
+
+/// where a language definition came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LanguageOrigin {
+    Builtin,
+    User(PathBuf),
+}
+
+/// a file format and the patterns used to recognize it
+#[derive(Clone, Debug)]
+pub struct Language {
+    pub name: String,
+    pub syntax: CommentSyntax,
+    pub filenames: Vec<String>,
+    pub globs: Vec<String>,
+    pub extensions: Vec<String>,
+    pub interpreters: Vec<String>,
+    pub origin: LanguageOrigin,
+}
+
+/// the rule which decided the comment syntax of a file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyntaxRule {
+    FileName(String),
+    Glob(String),
+    Extension(String),
+    Interpreter(String),
+    FirstLine(String),
+    Declaration(u32),
+    Modeline(String),
+    Fallback,
+}
+
+impl fmt::Display for SyntaxRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyntaxRule::FileName(name) => write!(f, "file name {}", name),
+            SyntaxRule::Glob(glob) => write!(f, "glob {}", glob),
+            SyntaxRule::Extension(extension) => write!(f, "extension .{}", extension),
+            SyntaxRule::Interpreter(interpreter) => write!(f, "interpreter {}", interpreter),
+            SyntaxRule::FirstLine(start) => write!(f, "first line {}", start),
+            SyntaxRule::Declaration(line) => write!(f, "declared on line {}", line),
+            SyntaxRule::Modeline(filetype) => write!(f, "modeline {}", filetype),
+            SyntaxRule::Fallback => write!(f, "default"),
+        }
+    }
+}
+
+/// result of comment syntax detection
+#[derive(Clone, Debug)]
+pub struct SyntaxMatch {
+    pub language: String,
+    pub syntax: CommentSyntax,
+    pub rule: SyntaxRule,
+    pub origin: LanguageOrigin,
+}
+
+impl fmt::Display for SyntaxMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {}{})",
+            self.syntax.to_string().bold(),
+            self.language,
+            self.rule,
+            match &self.origin {
+                LanguageOrigin::Builtin => String::new(),
+                LanguageOrigin::User(path) => format!(" from {}", path.display()),
+            }
+        )
+    }
+}
+
+pub struct LanguageRegistry {
+    // user languages come first so they take precedence
+    languages: Vec<Language>,
+}
+
+impl LanguageRegistry {
+    pub fn builtin() -> LanguageRegistry {
+        LanguageRegistry {
+            languages: BUILTIN_LANGUAGES
+                .iter()
+                .map(
+                    |(name, syntax, filenames, globs, extensions, interpreters)| Language {
+                        name: name.to_string(),
+                        syntax: CommentSyntax::from(*syntax),
+                        filenames: filenames.iter().map(|s| s.to_string()).collect(),
+                        globs: globs.iter().map(|s| s.to_string()).collect(),
+                        extensions: extensions.iter().map(|s| s.to_string()).collect(),
+                        interpreters: interpreters.iter().map(|s| s.to_string()).collect(),
+                        origin: LanguageOrigin::Builtin,
+                    },
+                )
+                .collect(),
+        }
+    }
+
+    /// built-in languages extended by the user's languages.toml
+    /// errors in the user file are reported and the file is ignored
+    pub fn load() -> LanguageRegistry {
+        let mut registry = LanguageRegistry::builtin();
+        let path = crate::xdg::config_home().join("imosid/languages.toml");
+        if !path.is_file() {
+            return registry;
+        }
+        let content = match read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("could not read {}: {}", path.display(), e);
+                return registry;
+            }
+        };
+        if let Err(e) = registry.add_toml(&content, &path) {
+            eprintln!("{} {}: {}", "invalid".red(), path.display(), e);
+        }
+        registry
+    }
+
+    /// registry shared by the whole process, loaded on first use
+    pub fn global() -> &'static LanguageRegistry {
+        static REGISTRY: OnceLock<LanguageRegistry> = OnceLock::new();
+        REGISTRY.get_or_init(LanguageRegistry::load)
+    }
+
+    /// add languages from toml, one table per language
+    ///
+    /// ```toml
+    /// [lua]
+    /// comment = "--"
+    /// extensions = ["lua"]
+    /// globs = ["*/nvim/*.conf"]
+    /// ```
+    pub fn add_toml(&mut self, content: &str, path: &Path) -> Result<(), String> {
+        let value = content.parse::<Value>().map_err(|e| e.to_string())?;
+        let table = value.as_table().ok_or("expected a table")?;
+        let mut languages = Vec::new();
+        for (name, definition) in table {
+            let comment = definition
+                .get("comment")
+                .and_then(Value::as_str)
+                .ok_or(format!("language {} has no comment syntax", name))?;
+            let get_list = |key: &str| -> Result<Vec<String>, String> {
+                match definition.get(key) {
+                    None => Ok(Vec::new()),
+                    Some(Value::Array(values)) => values
+                        .iter()
+                        .map(|v| {
+                            v.as_str()
+                                .map(String::from)
+                                .ok_or(format!("{}.{} must only contain strings", name, key))
+                        })
+                        .collect(),
+                    Some(_) => Err(format!("{}.{} must be a list", name, key)),
+                }
+            };
+            languages.push(Language {
+                name: name.clone(),
+                syntax: CommentSyntax::from(comment),
+                filenames: get_list("filenames")?,
+                globs: get_list("globs")?,
+                extensions: get_list("extensions")?,
+                interpreters: get_list("interpreters")?,
+                origin: LanguageOrigin::User(path.to_path_buf()),
+            });
+        }
+        languages.append(&mut self.languages);
+        self.languages = languages;
+        Ok(())
+    }
+
+    fn found(language: &Language, rule: SyntaxRule) -> SyntaxMatch {
+        SyntaxMatch {
+            language: language.name.clone(),
+            syntax: language.syntax.clone(),
+            rule,
+            origin: language.origin.clone(),
+        }
+    }
+
+    /// look up a language by the name an editor would use for it
+    pub fn find(&self, filetype: &str) -> Option<&Language> {
+        let filetype = filetype.to_lowercase();
+        let lookup = |filetype: &str| {
+            self.languages
+                .iter()
+                .find(|language| language.name == filetype)
+                .or_else(|| {
+                    self.languages
+                        .iter()
+                        .find(|language| language.extensions.iter().any(|e| e == filetype))
+                })
+                .or_else(|| {
+                    self.languages
+                        .iter()
+                        .find(|language| language.interpreters.iter().any(|i| i == filetype))
+                })
+        };
+        // emacs modes like emacs-lisp or sh-mode
+        lookup(&filetype).or_else(|| {
+            filetype
+                .trim_end_matches("-mode")
+                .rsplit('-')
+                .next()
+                .and_then(lookup)
+        })
+    }
+
+    // language of a file by its name, glob or extension, in that order
+    fn match_path(languages: &[&Language], filename: &str) -> Option<SyntaxMatch> {
+        let fpath = Path::new(filename);
+        let fname = fpath
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or("")
+            .trim_start_matches('.');
+
+        // get comment syntax via file name
+        for language in languages {
+            if language.filenames.iter().any(|name| name == fname) {
+                return Some(Self::found(
+                    language,
+                    SyntaxRule::FileName(fname.to_string()),
+                ));
+            }
+        }
+
+        for language in languages {
+            for glob in &language.globs {
+                if glob_matches(glob, filename) {
+                    return Some(Self::found(language, SyntaxRule::Glob(glob.clone())));
+                }
+            }
+        }
+
+        // get comment syntax via file extension
+        let extension = fpath.extension().and_then(OsStr::to_str)?;
+        for language in languages {
+            if language.extensions.iter().any(|e| e == extension) {
+                return Some(Self::found(
+                    language,
+                    SyntaxRule::Extension(extension.to_string()),
+                ));
+            }
+        }
+        None
+    }
+
+    // detect comment syntax for file based on declaration, filename, extension,
+    // hashbang and editor modelines
+    pub fn detect(&self, filename: &str, lines: &[&str]) -> SyntaxMatch {
+        let firstline = lines.first().copied().unwrap_or("");
+
+        // an explicit declaration always wins
+        for (index, line) in lines.iter().enumerate().take(header_length(lines)) {
+            if let Some(syntax) = parse_declaration(line) {
+                return SyntaxMatch {
+                    language: String::from("declared"),
+                    syntax,
+                    rule: SyntaxRule::Declaration(index as u32 + 1),
+                    origin: LanguageOrigin::Builtin,
+                };
+            }
+        }
+
+        // every rule of a user language wins over the built-in languages,
+        // a user glob overrides a built-in file name
+        let (user, builtin): (Vec<&Language>, Vec<&Language>) = self
+            .languages
+            .iter()
+            .partition(|language| language.origin != LanguageOrigin::Builtin);
+        for languages in [user, builtin] {
+            if let Some(found) = Self::match_path(&languages, filename) {
+                return found;
+            }
+        }
+
+        // get comment syntax via #!/hashbang
+        if let Some(interpreter) = get_interpreter(firstline) {
+            for language in &self.languages {
+                if language.interpreters.iter().any(|i| i == interpreter) {
+                    return Self::found(language, SyntaxRule::Interpreter(interpreter.to_string()));
+                }
+            }
+        }
+
+        // xml based formats announce themselves on the first line
+        for start in ["<?xml", "<!DOCTYPE"] {
+            if firstline.starts_with(start) {
+                return SyntaxMatch {
+                    language: String::from("xml"),
+                    syntax: CommentSyntax::block("<!--", "-->"),
+                    rule: SyntaxRule::FirstLine(start.to_string()),
+                    origin: LanguageOrigin::Builtin,
+                };
+            }
+        }
+
+        if let Some(filetype) = find_modeline(lines) {
+            if let Some(language) = self.find(filetype) {
+                return Self::found(language, SyntaxRule::Modeline(filetype.to_string()));
+            }
+        }
+
+        SyntaxMatch {
+            language: String::from("unknown"),
+            syntax: CommentSyntax::line("#"),
+            rule: SyntaxRule::Fallback,
+            origin: LanguageOrigin::Builtin,
+        }
+    }
+}
+
+// number of lines before the first one that cannot be a comment,
+// declarations are only searched there. the comment syntax is not known
+// yet, so every line starting with punctuation may be one
+fn header_length(lines: &[&str]) -> usize {
+    lines
+        .iter()
+        .position(|line| {
+            line.trim_start()
+                .chars()
+                .next()
+                .is_some_and(|first| !first.is_ascii_punctuation())
+        })
+        .unwrap_or(lines.len())
+}
+
+// syntax declared with a comment like `#... all syntax //`
+// the declaration may be written in any comment syntax, block comment
+// declarations may repeat the closing delimiter, `/*... all syntax /* */ */`
+fn parse_declaration(line: &str) -> Option<CommentSyntax> {
+    static DECLARATION: OnceLock<Regex> = OnceLock::new();
+    let regex = DECLARATION
+        .get_or_init(|| Regex::new(r"^\s*\S+?\s*\.\.\.\s*all\s+syntax\s+(.+?)\s*$").unwrap());
+    let mut tokens = regex
+        .captures(line)?
+        .get(1)?
+        .as_str()
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+    if tokens.len() == 3 && tokens[1] == tokens[2] {
+        tokens.pop();
+    }
+    if tokens.len() > 2 {
+        return None;
+    }
+    Some(CommentSyntax::from(tokens.join(" ").as_str()))
+}
+
+// file type from a vim modeline in the first or last five lines
+// or an emacs mode line in the first two lines
+fn find_modeline<'a>(lines: &[&'a str]) -> Option<&'a str> {
+    static VIM: OnceLock<Regex> = OnceLock::new();
+    static EMACS: OnceLock<Regex> = OnceLock::new();
+    let vim = VIM.get_or_init(|| {
+        Regex::new(r"(?:^|\s)(?:vim?|ex):.*?(?:^|[\s:])(?:ft|filetype)=([\w.+-]+)").unwrap()
+    });
+    let emacs = EMACS.get_or_init(|| {
+        Regex::new(r"-\*-\s*(?:.*?mode:\s*([\w+-]+)|([\w+-]+))\s*;?.*?-\*-").unwrap()
+    });
+
+    for line in lines.iter().take(2) {
+        if let Some(captures) = emacs.captures(line) {
+            if let Some(mode) = captures.get(1).or(captures.get(2)) {
+                return Some(mode.as_str());
+            }
+        }
+    }
+    let tail = lines.len().saturating_sub(5).max(5);
+    for line in lines.iter().take(5).chain(lines.iter().skip(tail)) {
+        if let Some(captures) = vim.captures(line) {
+            return Some(captures.get(1)?.as_str());
+        }
+    }
+    None
+}
+
+// name of the program in a hashbang, skipping env
+fn get_interpreter(firstline: &str) -> Option<&str> {
+    let mut words = firstline.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+    Some(program)
+}
+
+// globs without a slash only match the file name,
+// other globs have to match the end of the path
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let mut pattern = String::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Regex::new(&format!("(^|/){}$", pattern))
+        .map(|regex| regex.is_match(path))
+        .unwrap_or(false)
+}
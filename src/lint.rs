@@ -0,0 +1,124 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use serde_json::{json, Value};
+
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::dotwalker::walk_config_dir;
+use crate::files::DotFile;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LintFormat {
+    Human,
+    Json,
+    Quickfix,
+}
+
+impl From<&str> for LintFormat {
+    fn from(format: &str) -> Self {
+        match format {
+            "json" => LintFormat::Json,
+            "quickfix" => LintFormat::Quickfix,
+            _ => LintFormat::Human,
+        }
+    }
+}
+
+/// all problems found in a file or directory
+pub struct LintReport {
+    pub files: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl LintReport {
+    pub fn new(path: &PathBuf) -> LintReport {
+        let mut report = LintReport {
+            files: 0,
+            diagnostics: Vec::new(),
+        };
+        if path.is_dir() {
+            for entry in walk_config_dir(path) {
+                report.lint_file(entry.path());
+            }
+        } else {
+            report.lint_file(path);
+        }
+        report
+    }
+
+    fn lint_file(&mut self, path: &Path) {
+        let filename = path.to_string_lossy();
+        match DotFile::from_pathbuf(&path.to_path_buf()) {
+            Ok(dotfile) => {
+                for mut diagnostic in dotfile.diagnostics {
+                    // report paths the way the user passed them in
+                    diagnostic.file = filename.to_string();
+                    self.diagnostics.push(diagnostic);
+                }
+            }
+            // binary files cannot contain markers
+            Err(e) if e.kind() == ErrorKind::InvalidData => return,
+            Err(e) => {
+                let mut diagnostic =
+                    Diagnostic::error("unreadable", 0, &format!("could not read file: {}", e));
+                diagnostic.file = filename.to_string();
+                self.diagnostics.push(diagnostic);
+            }
+        }
+        self.files += 1;
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == severity)
+            .count()
+    }
+
+    pub fn output(&self, format: LintFormat) -> String {
+        let errors = self.count(Severity::Error);
+        let warnings = self.count(Severity::Warning);
+        match format {
+            LintFormat::Human => {
+                let mut ret = String::new();
+                for diagnostic in &self.diagnostics {
+                    ret.push_str(&diagnostic.render());
+                    ret.push('\n');
+                }
+                let summary = format!(
+                    "checked {} files: {} errors, {} warnings",
+                    self.files, errors, warnings
+                );
+                ret.push_str(&if errors > 0 {
+                    summary.red().bold().to_string()
+                } else if warnings > 0 {
+                    summary.yellow().bold().to_string()
+                } else {
+                    summary.green().bold().to_string()
+                });
+                ret.push('\n');
+                ret
+            }
+            LintFormat::Json => {
+                let diagnostics = self
+                    .diagnostics
+                    .iter()
+                    .map(Diagnostic::json)
+                    .collect::<Vec<Value>>();
+                let report = json!({
+                    "files": self.files,
+                    "errors": errors,
+                    "warnings": warnings,
+                    "diagnostics": diagnostics,
+                });
+                format!("{}\n", report)
+            }
+            LintFormat::Quickfix => self
+                .diagnostics
+                .iter()
+                .map(|diagnostic| format!("{}\n", diagnostic.quickfix()))
+                .collect(),
+        }
+    }
+}
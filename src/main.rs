@@ -1,23 +1,48 @@
+#![allow(clippy::needless_return)]
+
 mod app;
 mod dotwalker;
 mod test;
 use colored::Colorize;
 use dotwalker::{walk_config_dir, walk_dotfiles};
+mod atomicwrite;
+mod backup;
+mod cache;
+mod command;
 mod comment;
 mod commentmap;
 mod contentline;
+mod diagnostic;
+mod diff;
 mod files;
 mod hashable;
+mod journal;
+mod languages;
+mod lint;
+mod merge;
 mod metafile;
+mod outdated;
 mod section;
-use std::{path::PathBuf, println};
+mod session;
+mod source;
+mod store;
+mod timestamp;
+mod transaction;
+mod xdg;
+use std::{collections::HashSet, io::ErrorKind, path::PathBuf, println};
 
 use crate::{
-    app::get_vec_args,
-    files::{ApplyResult, DotFile},
+    app::{get_conflict_style, get_marker_style, get_vec_args, is_dry_run},
+    command::CommandPolicy,
+    diagnostic::Severity,
+    files::{ApplyResult, DotFile, UpdateOptions},
     hashable::Hashable,
+    journal::LogFilter,
+    lint::{LintFormat, LintReport},
     metafile::MetaFile,
-    section::Section,
+    outdated::{OutdatedFormat, OutdatedReport},
+    session::Session,
+    transaction::ApplyTransaction,
 };
 
 pub mod built_info {
@@ -36,10 +61,23 @@ macro_rules! check_file_arg {
     };
 }
 
+// the file on disk is left as it was if writing fails
+macro_rules! write_file {
+    ($file:expr, $name:expr, $session:expr) => {
+        if let Err(e) = $file.write_to_file($session) {
+            eprintln!("could not write {}: {}", $name.to_str().unwrap().red(), e);
+            std::process::exit(1);
+        }
+    };
+}
+
 macro_rules! get_dotfile {
     ($a:expr) => {
         match DotFile::from_pathbuf($a) {
-            Ok(file) => file,
+            Ok(file) => {
+                file.print_diagnostics(Severity::Warning);
+                file
+            }
             Err(_) => {
                 eprintln!("could not open file {}", $a.to_str().unwrap().red());
                 return Ok(());
@@ -48,25 +86,48 @@ macro_rules! get_dotfile {
     };
 }
 
+// dry runs exit with 2 if anything would have been written
+fn exit_dry_run(pending: bool) -> ! {
+    std::process::exit(if pending { 2 } else { 0 });
+}
+
 fn main() -> Result<(), std::io::Error> {
     let imosidapp = app::build_app();
     let matches = imosidapp.get_matches();
+    let mut session = Session::open();
+    if let Some(command) = matches.subcommand_name() {
+        session.journal.command = String::from(command);
+    }
 
     match matches.subcommand() {
         // compile a file, making it an unmodified imosid file
         Some(("compile", compile_matches)) => {
             let filename = compile_matches.get_one::<PathBuf>("file").unwrap();
+            let dryrun = is_dry_run(compile_matches);
             check_file_arg!(filename);
             if *compile_matches.get_one("metafile").unwrap() {
-                let mut newmetafile = MetaFile::from(filename.to_path_buf());
+                let mut newmetafile = MetaFile::read(filename.to_path_buf());
                 newmetafile.compile();
-                newmetafile.write_to_file();
+                if dryrun {
+                    let diff = newmetafile.pending_diff();
+                    print!("{}", diff);
+                    exit_dry_run(!diff.is_empty());
+                }
+                write_file!(newmetafile, filename, &mut session);
                 println!("compiled {}", &filename.to_str().unwrap().bold());
                 return Ok(());
             }
             let mut compfile = get_dotfile!(filename);
-            if compfile.compile() {
-                compfile.write_to_file();
+            let restyled = match get_marker_style(compile_matches) {
+                Some(style) => compfile.set_marker_style(style),
+                None => false,
+            };
+            if compfile.compile() || restyled {
+                if dryrun {
+                    print!("{}", compfile.pending_diff());
+                    exit_dry_run(true);
+                }
+                write_file!(compfile, filename, &mut session);
                 println!("compiled {}", filename.to_str().unwrap().bold());
             } else {
                 println!(
@@ -98,6 +159,110 @@ fn main() -> Result<(), std::io::Error> {
                     )
                 }
             }
+            if anymodified {
+                std::process::exit(1);
+            }
+        }
+
+        Some(("lint", lint_matches)) => {
+            let path = lint_matches.get_one::<PathBuf>("path").unwrap();
+            if !path.exists() {
+                eprintln!("{}", "file does not exist".red().bold());
+                std::process::exit(1);
+            }
+            let format =
+                LintFormat::from(lint_matches.get_one::<String>("format").unwrap().as_str());
+            let report = LintReport::new(path);
+            print!("{}", report.output(format));
+
+            if report.count(Severity::Error) > 0
+                || (*lint_matches.get_one::<bool>("deny-warnings").unwrap()
+                    && report.count(Severity::Warning) > 0)
+            {
+                std::process::exit(1);
+            }
+        }
+
+        Some(("outdated", outdated_matches)) => {
+            let path = outdated_matches.get_one::<PathBuf>("path").unwrap();
+            if !path.exists() {
+                eprintln!("{}", "file does not exist".red().bold());
+                std::process::exit(2);
+            }
+            let format = OutdatedFormat::from(
+                outdated_matches
+                    .get_one::<String>("format")
+                    .unwrap()
+                    .as_str(),
+            );
+            let offline = *outdated_matches.get_one::<bool>("offline").unwrap();
+            let report = OutdatedReport::new(path, offline, &session);
+            print!("{}", report.output(format));
+            std::process::exit(report.exit_code());
+        }
+
+        Some(("fmt", fmt_matches)) => {
+            let path = fmt_matches.get_one::<PathBuf>("path").unwrap();
+            let check = *fmt_matches.get_one::<bool>("check").unwrap();
+            let dryrun = is_dry_run(fmt_matches);
+            let files: Vec<PathBuf> = if path.is_dir() {
+                walk_config_dir(path)
+                    .map(|entry| entry.path().to_path_buf())
+                    .collect()
+            } else if path.is_file() {
+                vec![path.to_path_buf()]
+            } else {
+                eprintln!("{}", "file does not exist".red().bold());
+                std::process::exit(1);
+            };
+
+            let mut failed = false;
+            let mut pending = false;
+            for filename in files {
+                let mut dotfile = match DotFile::from_pathbuf(&filename) {
+                    Ok(file) => file,
+                    // binary files have no markers to format
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidData => continue,
+                    Err(_) => {
+                        eprintln!("could not open file {}", filename.to_str().unwrap().red());
+                        failed = true;
+                        continue;
+                    }
+                };
+                let name = filename.to_str().unwrap();
+                // broken markers could end up as content, leave them to the user
+                if dotfile
+                    .diagnostics
+                    .iter()
+                    .any(|diagnostic| diagnostic.severity == Severity::Error)
+                {
+                    dotfile.print_diagnostics(Severity::Error);
+                    eprintln!("{} {}", "skipping".red(), name.red().bold());
+                    failed = true;
+                    continue;
+                }
+                if !dotfile.format() {
+                    continue;
+                }
+                if dryrun {
+                    print!("{}", dotfile.pending_diff());
+                    pending = true;
+                } else if check {
+                    println!("{} {}", name.yellow().bold(), "needs formatting".yellow());
+                    failed = true;
+                } else if let Err(e) = dotfile.write_to_file(&mut session) {
+                    eprintln!("could not write {}: {}", name.red(), e);
+                    failed = true;
+                } else {
+                    println!("formatted {}", name.bold());
+                }
+            }
+            if failed {
+                std::process::exit(1);
+            }
+            if dryrun {
+                exit_dry_run(pending);
+            }
         }
 
         Some(("query", query_matches)) => {
@@ -109,17 +274,19 @@ fn main() -> Result<(), std::io::Error> {
             let queryfile = get_dotfile!(filename);
 
             if queryfile.metafile.is_some() {
-                todo!("add message for this");
+                eprintln!(
+                    "{} is managed by a metafile and has no sections",
+                    filename.to_str().unwrap().yellow()
+                );
                 return Ok(());
             }
 
-            for i in &queryfile.sections {
-                if let Section::Named(_, named_data) = i {
-                    for query in &query_sections {
-                        if query.eq(&named_data.name) {
-                            println!("{}", i.output(&queryfile.commentsign));
-                        }
-                    }
+            for query in &query_sections {
+                if let Some(section) = queryfile.get_section(query) {
+                    print!(
+                        "{}",
+                        section.output(&queryfile.commentsign, queryfile.markerstyle)
+                    );
                 }
             }
         }
@@ -128,52 +295,94 @@ fn main() -> Result<(), std::io::Error> {
             let filename = update_matches.get_one::<PathBuf>("file").unwrap();
 
             let sections = get_vec_args(update_matches, "section");
+            let printmode = *update_matches.get_one::<bool>("print").unwrap();
+            let dryrun = is_dry_run(update_matches);
+            let options = UpdateOptions {
+                offline: *update_matches.get_one::<bool>("offline").unwrap(),
+                trust: *update_matches.get_one::<bool>("trust").unwrap(),
+                accept_new: *update_matches.get_one::<bool>("accept-new").unwrap(),
+                conflicts: get_conflict_style(update_matches),
+                commands: CommandPolicy::load(),
+                dry_run: dryrun,
+            };
 
             check_file_arg!(filename);
 
             let mut updatefile = get_dotfile!(filename);
-            updatefile.update();
-
-            match updatefile.metafile {
-                Some(_) => {
-                    eprintln!("cannot update metafile");
-                    return Ok(());
-                }
-                None => {}
-            }
+            let changed = updatefile.update(&sections, &options, &session);
 
-            if sections.is_empty() {
-                // update all sections
+            if dryrun {
+                let diff = updatefile.pending_diff();
+                print!("{}", diff);
+                exit_dry_run(!diff.is_empty());
+            } else if printmode {
+                print!("{}", updatefile);
+            } else if changed {
+                write_file!(updatefile, filename, &mut session);
+                println!("updated {}", filename.to_str().unwrap().bold());
             }
         }
         Some(("delete", delete_matches)) => {
             let filename = delete_matches.get_one::<PathBuf>("file").unwrap();
 
             let sections = get_vec_args(delete_matches, "section");
+            let printmode = *delete_matches.get_one::<bool>("print").unwrap();
+            let dryrun = is_dry_run(delete_matches);
 
             check_file_arg!(filename);
 
             let mut deletefile = get_dotfile!(filename);
 
             for i in sections {
-                if deletefile.deletesection(i) {
+                if !deletefile.deletesection(i) {
+                    eprintln!("could not find section {}", i.red());
+                } else if !dryrun && !printmode {
                     println!("deleted section {}", i.bold());
-                } else {
-                    println!("could not find section {}", i.red());
                 }
             }
-            deletefile.write_to_file();
+            if dryrun {
+                let diff = deletefile.pending_diff();
+                print!("{}", diff);
+                exit_dry_run(!diff.is_empty());
+            } else if printmode {
+                print!("{}", deletefile);
+            } else {
+                write_file!(deletefile, filename, &mut session);
+            }
         }
 
         Some(("apply", apply_matches)) => {
             let mut donesomething = false;
+            let conflicts = get_conflict_style(apply_matches);
+            let dryrun = is_dry_run(apply_matches);
             let filename = apply_matches.get_one::<PathBuf>("file").unwrap();
+            if *apply_matches.get_one::<bool>("all-or-nothing").unwrap() {
+                let paths: Vec<PathBuf> = if filename.is_dir() {
+                    walk_config_dir(filename)
+                        .map(|entry| entry.path().to_path_buf())
+                        .collect()
+                } else if filename.is_file() {
+                    vec![filename.to_path_buf()]
+                } else {
+                    eprintln!("{}", "file does not exist".red().bold());
+                    std::process::exit(1);
+                };
+                let mut transaction = ApplyTransaction::plan(&paths, conflicts, &session);
+                if dryrun && transaction.errors.is_empty() {
+                    exit_dry_run(transaction.preview());
+                }
+                if !transaction.commit(&mut session) {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
             if filename.is_dir() {
                 for entry in walk_config_dir(filename) {
-                    let entrypath = entry.path().to_path_buf();
-                    let entrystring = entry.path().to_str();
                     let tmpsource = match DotFile::from_pathbuf(&entry.path().to_path_buf()) {
-                        Ok(file) => file,
+                        Ok(file) => {
+                            file.print_diagnostics(Severity::Warning);
+                            file
+                        }
                         Err(_) => {
                             eprintln!(
                                 "could not open file {}",
@@ -182,30 +391,180 @@ fn main() -> Result<(), std::io::Error> {
                             continue;
                         }
                     };
-                    match tmpsource.apply() {
-                        ApplyResult::Changed => {
-                            donesomething = true;
-                        }
-                        _ => {}
+                    if let ApplyResult::Changed = tmpsource.apply(conflicts, dryrun, &mut session) {
+                        donesomething = true;
                     }
                 }
+                if dryrun {
+                    exit_dry_run(donesomething);
+                }
                 if !donesomething {
                     println!("{}", "nothing to do".bold());
                 }
                 return Ok(());
             } else if filename.is_file() {
                 let tmpsource = get_dotfile!(filename);
-                tmpsource.apply();
+                let result = tmpsource.apply(conflicts, dryrun, &mut session);
+                if dryrun {
+                    exit_dry_run(matches!(result, ApplyResult::Changed));
+                }
             } else {
                 eprintln!("{}", "file does not exist".red().bold());
                 return Ok(());
             }
         }
+        Some(("show", show_matches)) => {
+            let hash = show_matches.get_one::<String>("hash").unwrap();
+            match session.store.get(hash) {
+                Some(content) => print!("{}", content),
+                None => {
+                    eprintln!("no content stored for {}", hash.red());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("diff", diff_matches)) => {
+            let filename = diff_matches.get_one::<PathBuf>("original").unwrap();
+            check_file_arg!(filename);
+            let difffile = get_dotfile!(filename);
+            print!("{}", difffile.original_diff(&session.store));
+        }
+        Some(("gc", gc_matches)) => {
+            let store = &session.store;
+            let mut referenced = HashSet::new();
+            // keeping too little would lose the originals of files, so
+            // anything that cannot be read stops gc
+            let mut keep = |path: &PathBuf| match DotFile::from_pathbuf(path) {
+                Ok(dotfile) => {
+                    referenced.extend(dotfile.stored_hashes());
+                    true
+                }
+                // gone, and the originals of it are not needed anymore
+                Err(e) if e.kind() == ErrorKind::NotFound => false,
+                // binary files cannot refer to contents
+                Err(e) if e.kind() == ErrorKind::InvalidData => false,
+                Err(e) => {
+                    eprintln!(
+                        "{} {}: {}, nothing was removed",
+                        "could not read".red(),
+                        path.display(),
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            };
+            let mut managed = store.managed()?;
+            managed.retain(|path| keep(path));
+            for path in gc_matches.get_many::<PathBuf>("path").unwrap_or_default() {
+                if !path.exists() {
+                    eprintln!("{} does not exist", path.to_str().unwrap().red());
+                    std::process::exit(1);
+                }
+                for entry in walk_config_dir(path) {
+                    keep(&entry.path().to_path_buf());
+                }
+            }
+            let dryrun = is_dry_run(gc_matches);
+            let printmode = *gc_matches.get_one::<bool>("print").unwrap() || dryrun;
+            if !printmode {
+                store.set_managed(&managed)?;
+            }
+            let mut removed = 0;
+            for hash in store.hashes()? {
+                if referenced.contains(&hash) {
+                    continue;
+                }
+                if printmode {
+                    println!("{}", hash);
+                } else {
+                    store.remove(&hash)?;
+                }
+                removed += 1;
+            }
+            if dryrun {
+                exit_dry_run(removed > 0);
+            }
+            if !printmode {
+                println!("removed {} stored contents", removed);
+            }
+        }
+        Some(("backups", _)) => {
+            for set in session.backups.list()? {
+                println!("{}  {}", set.id.bold(), set.command);
+                for entry in &set.files {
+                    if entry.existed() {
+                        println!("    {}", entry.path.display());
+                    } else {
+                        println!("    {} {}", entry.path.display(), "(created)".yellow());
+                    }
+                }
+            }
+        }
+        Some(("rollback", rollback_matches)) => {
+            let dryrun = is_dry_run(rollback_matches);
+            let paths = rollback_matches
+                .get_many::<PathBuf>("path")
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<PathBuf>>();
+            let to = rollback_matches
+                .get_one::<String>("to")
+                .map(|id| id.as_str());
+            match session
+                .backups
+                .rollback(to, &paths, dryrun, &session.journal)
+            {
+                Ok(restored) => {
+                    for path in &restored {
+                        let verb = if dryrun { "would restore" } else { "restored" };
+                        println!("{} {}", verb, path.display().to_string().bold());
+                    }
+                    if dryrun {
+                        exit_dry_run(!restored.is_empty());
+                    }
+                    if restored.is_empty() {
+                        println!("{}", "nothing to do".bold());
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e.red());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("log", log_matches)) => {
+            let since = match log_matches.get_one::<String>("since") {
+                Some(since) => match timestamp::parse_since(since, timestamp::now()) {
+                    Ok(since) => Some(since),
+                    Err(e) => {
+                        eprintln!("{}", e.red());
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
+            };
+            let filter = LogFilter {
+                file: log_matches.get_one::<PathBuf>("file").cloned(),
+                section: log_matches.get_one::<String>("section").cloned(),
+                since,
+            };
+            let entries = match session.journal.entries() {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("{}", e.red());
+                    std::process::exit(1);
+                }
+            };
+            for entry in entries.iter().filter(|entry| filter.matches(entry)) {
+                print!("{}", entry.pretty());
+            }
+        }
         Some(("info", info_matches)) => {
             let filename = info_matches.get_one::<PathBuf>("file").unwrap();
             check_file_arg!(filename);
             let infofile = DotFile::from_pathbuf(filename)?;
             println!("{}", infofile.pretty_info());
+            infofile.print_diagnostics(Severity::Warning);
 
             if infofile.modified {
                 // give caller an easy way to tell if a file is modified
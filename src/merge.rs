@@ -0,0 +1,188 @@
+/// result of a three way merge
+pub struct Merged {
+    pub content: String,
+    pub conflicts: usize, // number of conflicting chunks, marked in content
+}
+
+/// merge two changed versions of the same text line by line
+/// chunks changed on both sides in different ways are written with
+/// conflict markers, like git does
+pub fn merge(base: &str, local: &str, upstream: &str) -> Merged {
+    let newline = if local.contains("\r\n") { "\r\n" } else { "\n" };
+    let base = lines(base);
+    let local = lines(local);
+    let upstream = lines(upstream);
+    let to_local = matching_lines(&base, &local);
+    let to_upstream = matching_lines(&base, &upstream);
+
+    let mut merged = Merged {
+        content: String::new(),
+        conflicts: 0,
+    };
+    let (mut i, mut a, mut b) = (0, 0, 0);
+    loop {
+        // lines unchanged on both sides
+        if i < base.len() && to_local[i] == Some(a) && to_upstream[i] == Some(b) {
+            merged.content.push_str(base[i]);
+            i += 1;
+            a += 1;
+            b += 1;
+            continue;
+        }
+        if i == base.len() && a == local.len() && b == upstream.len() {
+            return merged;
+        }
+
+        // the changed chunk ends at the next line both sides kept
+        let (k, ka, kb) = (i..base.len())
+            .find_map(|k| match (to_local[k], to_upstream[k]) {
+                (Some(ka), Some(kb)) => Some((k, ka, kb)),
+                _ => None,
+            })
+            .unwrap_or((base.len(), local.len(), upstream.len()));
+        let (basechunk, localchunk, upstreamchunk) = (&base[i..k], &local[a..ka], &upstream[b..kb]);
+        if localchunk == basechunk || localchunk == upstreamchunk {
+            merged.content.push_str(&upstreamchunk.concat());
+        } else if upstreamchunk == basechunk {
+            merged.content.push_str(&localchunk.concat());
+        } else {
+            merged.conflicts += 1;
+            merged
+                .content
+                .push_str(&format!("<<<<<<< local{}", newline));
+            push_chunk(&mut merged.content, localchunk, newline);
+            merged.content.push_str(&format!("======={}", newline));
+            push_chunk(&mut merged.content, upstreamchunk, newline);
+            merged
+                .content
+                .push_str(&format!(">>>>>>> upstream{}", newline));
+        }
+        (i, a, b) = (k, ka, kb);
+    }
+}
+
+// lines including their line endings
+fn lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+// a chunk without a final line ending would swallow the next marker
+fn push_chunk(content: &mut String, chunk: &[&str], newline: &str) {
+    content.push_str(&chunk.concat());
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push_str(newline);
+    }
+}
+
+// for every base line the index of the same line in other,
+// if it is part of the longest common subsequence
+pub fn matching_lines(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    match_lines(base, other, (0, 0), &mut matches);
+    matches
+}
+
+// match the lines of base and other, which start at the given offsets
+// of the whole texts. lines both start or end with are matched right away,
+// for the rest the middle of a shortest edit script is searched and both
+// halves are matched on their own, so memory stays linear
+fn match_lines(
+    base: &[&str],
+    other: &[&str],
+    offsets: (usize, usize),
+    matches: &mut [Option<usize>],
+) {
+    let prefix = base.iter().zip(other).take_while(|(a, b)| a == b).count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(other[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    for index in 0..prefix {
+        matches[offsets.0 + index] = Some(offsets.1 + index);
+    }
+    for index in 1..=suffix {
+        matches[offsets.0 + base.len() - index] = Some(offsets.1 + other.len() - index);
+    }
+
+    let base = &base[prefix..base.len() - suffix];
+    let other = &other[prefix..other.len() - suffix];
+    if base.is_empty() || other.is_empty() {
+        return;
+    }
+    let (a, b) = (offsets.0 + prefix, offsets.1 + prefix);
+    let (x, y, u, v) = middle_snake(base, other);
+    match_lines(&base[..x], &other[..y], (a, b), matches);
+    for index in x..u {
+        matches[a + index] = Some(b + y + index - x);
+    }
+    match_lines(&base[u..], &other[v..], (a + u, b + v), matches);
+}
+
+// start and end of the diagonal in the middle of a shortest edit script
+// from a to b, found by searching from both ends at once
+// see myers, an o(nd) difference algorithm and its variations, 4b
+fn middle_snake(a: &[&str], b: &[&str]) -> (usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let odd = delta % 2 != 0;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // furthest x reached on every diagonal k = x - y, from the start
+    // and from the end, where x counts lines from the end
+    let mut forward = vec![0isize; (2 * offset + 1) as usize];
+    let mut backward = vec![0isize; (2 * offset + 1) as usize];
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && forward[i - 1] < forward[i + 1]) {
+                forward[i + 1]
+            } else {
+                forward[i - 1] + 1
+            };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[i] = x;
+            let reverse = delta - k;
+            if odd
+                && (-(d - 1)..=d - 1).contains(&reverse)
+                && x + backward[(reverse + offset) as usize] >= n
+            {
+                return (x0 as usize, y0 as usize, x as usize, y as usize);
+            }
+        }
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && backward[i - 1] < backward[i + 1]) {
+                backward[i + 1]
+            } else {
+                backward[i - 1] + 1
+            };
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[i] = x;
+            let forwardk = delta - k;
+            if !odd
+                && (-d..=d).contains(&forwardk)
+                && x + forward[(forwardk + offset) as usize] >= n
+            {
+                return (
+                    (n - x) as usize,
+                    (m - y) as usize,
+                    (n - x0) as usize,
+                    (m - y0) as usize,
+                );
+            }
+        }
+    }
+    unreachable!("the searches from both ends always meet")
+}
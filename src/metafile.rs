@@ -1,12 +1,15 @@
+use crate::atomicwrite::write_atomic;
 use crate::built_info;
+use crate::diff::unified_diff;
 use crate::hashable::{ChangeState, Hashable};
+use crate::journal::JournalEntry;
+use crate::session::Session;
 use colored::Colorize;
 use semver::Version;
 use sha256::digest;
-use std::fs::{self, read_to_string, File};
-use std::io::Write;
-use std::os::unix::prelude::PermissionsExt;
-use std::path::PathBuf;
+use std::fs::{self, read_to_string};
+use std::io;
+use std::path::{Path, PathBuf};
 use toml::Value;
 
 // a file containing metadata about an imosid file for file types which do not support comments
@@ -16,6 +19,8 @@ pub struct MetaFile {
     pub parentfile: String,
     pub targetfile: Option<String>,
     pub sourcefile: Option<String>,
+    pub sourcecommit: Option<String>, // commit a git source was last read from
+    pub sourcepin: Option<String>,    // sha256 the content of the source has to have
     pub modified: bool,
     imosidversion: Version,
     syntaxversion: i64,
@@ -56,6 +61,8 @@ impl MetaFile {
             currenthash: String::from(""),
             targetfile: None,
             sourcefile: None,
+            sourcecommit: None,
+            sourcepin: None,
             hash: String::from(""),
             parentfile: String::from(""),
             // default version strings
@@ -80,13 +87,21 @@ impl MetaFile {
             retfile.sourcefile = Some(String::from(sourcefile));
         }
 
+        if let Some(Value::String(sourcecommit)) = value.get("commit") {
+            retfile.sourcecommit = Some(String::from(sourcecommit));
+        }
+
+        if let Some(Value::String(sourcepin)) = value.get("sha256") {
+            retfile.sourcepin = Some(String::from(sourcepin));
+        }
+
         if let Some(Value::Integer(permissions)) = value.get("permissions") {
             //TODO check if permissions smaller than 777
             retfile.permissions = Some(*permissions as u32);
         }
 
         if let Some(Value::Integer(syntaxversion)) = value.get("syntaxversion") {
-            retfile.syntaxversion = syntaxversion.clone();
+            retfile.syntaxversion = *syntaxversion;
         }
 
         if let Some(Value::String(imosidversion)) = value.get("imosidversion") {
@@ -98,76 +113,55 @@ impl MetaFile {
         Some(retfile)
     }
 
-    fn get_parent_file(&self) -> PathBuf {
-        let mut path = self.path.clone();
-        path.push(&self.parentfile);
-        path.pop();
-        path
-    }
-
-    // TODO incorporate this into normal write
-    pub fn write_permissions(&self) {
-        let parentpath = self.get_parent_file();
-        if let Some(permissions) = &self.permissions {
-            let mut perms = fs::metadata(&parentpath).unwrap().permissions();
-            let permint = u32::from_str_radix(&format!("{}", permissions + 1000000), 8).unwrap();
-            perms.set_mode(permint);
-            fs::set_permissions(&parentpath, perms).expect("failed to set permissions");
-        } else {
-            println!("no permissions");
-        }
-    }
-
-    // create a new metafile for a file
+    // the metafile of a file, a compiled new one if there is none
+    // nothing is written to disk
     // TODO maybe return result?
-    // TODO split this up, this doesn't need to write to disk
-    pub fn from(sourcepath: PathBuf) -> MetaFile {
-        let mut path = sourcepath.clone();
-        //
+    pub fn read(sourcepath: PathBuf) -> MetaFile {
         //TODO handle result
         let filecontent =
-            read_to_string(&path).expect("could not read file content to create metafile");
+            read_to_string(&sourcepath).expect("could not read file content to create metafile");
 
-        let parentname = path
-            .file_name()
-            .unwrap()
-            .to_os_string()
-            .into_string()
-            .unwrap();
-
-        //TODO don't create metafiles for metafiles
-        let filename = format!("{}.imosid.toml", parentname);
-
-        path.pop();
-        path.push(filename);
-
-        let mut retfile: MetaFile;
+        let path = metafile_path(&sourcepath);
         //Maybe distinguish between new and from path?
         if path.is_file() {
-            retfile = MetaFile::new(path.clone(), &filecontent).expect("could not create metafile");
+            let mut retfile =
+                MetaFile::new(path.clone(), &filecontent).expect("could not create metafile");
             retfile.update();
             retfile.finalize();
+            retfile
         } else {
-            retfile = MetaFile {
-                currenthash: String::from(""),
-                targetfile: None,
-                sourcefile: None,
-                hash: String::from(""),
-                parentfile: String::from(&parentname),
-                imosidversion: Version::parse(built_info::PKG_VERSION).unwrap(),
-                syntaxversion: 0,
-                value: Value::Integer(0),
-                content: String::from(&filecontent),
-                modified: false,
-                permissions: Option::None,
-                path,
-            };
-
-            retfile.update();
-            retfile.compile();
-            retfile.write_to_file();
+            MetaFile::from_content(&sourcepath, &filecontent)
         }
+    }
 
+    // a compiled new metafile for a file with this content,
+    // neither of them has to exist yet
+    pub fn from_content(sourcepath: &Path, content: &str) -> MetaFile {
+        let parentname = sourcepath
+            .file_name()
+            .unwrap()
+            .to_os_string()
+            .into_string()
+            .unwrap();
+        let mut retfile = MetaFile {
+            currenthash: String::from(""),
+            targetfile: None,
+            sourcefile: None,
+            sourcecommit: None,
+            sourcepin: None,
+            hash: String::from(""),
+            parentfile: parentname,
+            imosidversion: Version::parse(built_info::PKG_VERSION).unwrap(),
+            syntaxversion: 0,
+            value: Value::Integer(0),
+            content: String::from(content),
+            modified: false,
+            permissions: Option::None,
+            path: metafile_path(sourcepath),
+        };
+
+        retfile.update();
+        retfile.compile();
         retfile
     }
 
@@ -178,7 +172,7 @@ impl MetaFile {
     // populate toml value with data
     fn update(&mut self) {
         let mut selfmap = toml::map::Map::new();
-        selfmap.insert("hash".into(), Value::String((&self.hash).to_string()));
+        selfmap.insert("hash".into(), Value::String(self.hash.to_string()));
         selfmap.insert("parent".into(), Value::String((&self.parentfile).into()));
 
         if let Some(targetfile) = &self.targetfile {
@@ -194,6 +188,25 @@ impl MetaFile {
             );
         }
 
+        if let Some(sourcecommit) = &self.sourcecommit {
+            selfmap.insert(
+                String::from("commit"),
+                Value::String(String::from(sourcecommit)),
+            );
+        }
+        if let Some(sourcepin) = &self.sourcepin {
+            selfmap.insert(
+                String::from("sha256"),
+                Value::String(String::from(sourcepin)),
+            );
+        }
+        if let Some(permissions) = self.permissions {
+            selfmap.insert(
+                String::from("permissions"),
+                Value::Integer(permissions.into()),
+            );
+        }
+
         // TODO: store syntax version somewhere central
         selfmap.insert(String::from("syntaxversion"), Value::Integer(0));
 
@@ -211,20 +224,50 @@ impl MetaFile {
 
     pub fn output(&mut self) -> String {
         self.update();
-        self.value.to_string()
+        // a table has to be written as a document, not as an inline table
+        match &self.value {
+            Value::Table(table) => table.to_string(),
+            value => value.to_string(),
+        }
     }
 
-    pub fn write_to_file(&mut self) {
-        let newfile = File::create(&self.path);
-        match newfile {
-            Err(_) => {
-                eprintln!("{}", "Error: could not write metafile".red());
-            }
-            Ok(mut file) => {
-                file.write_all(self.output().as_bytes())
-                    .expect("could not write metafile");
+    pub fn write_to_file(&mut self, session: &mut Session) -> io::Result<()> {
+        let output = self.output();
+        let path = self.path.to_string_lossy().to_string();
+        let parent = Path::new(path.strip_suffix(".imosid.toml").unwrap_or(&path));
+        // the metafile is written from the file it describes
+        let entry = JournalEntry::new(
+            &self.path,
+            fs::read(&self.path).ok().as_deref(),
+            Some(output.as_bytes()),
+        )
+        .with_source(parent);
+        let result = session
+            .snapshot(&self.path)
+            .and_then(|_| write_atomic(&self.path, output.as_bytes(), None));
+        match &result {
+            Ok(()) => session.journal.record(&entry),
+            Err(e) => session.journal.record(&entry.failed(&e.to_string())),
+        }
+        result?;
+        // the content the hash stands for, to compare and merge with later
+        let store = &session.store;
+        if let Err(e) = store.add_managed(parent) {
+            eprintln!("could not remember {} for gc: {}", self.parentfile, e);
+        }
+        if !self.modified {
+            if let Err(e) = store.put(&self.content) {
+                eprintln!("could not store content of {}: {}", self.parentfile, e);
             }
         }
+        Ok(())
+    }
+
+    /// unified diff between the metafile on disk and what write_to_file would write
+    pub fn pending_diff(&mut self) -> String {
+        let ondisk = read_to_string(&self.path).unwrap_or_default();
+        let name = self.path.to_string_lossy().to_string();
+        unified_diff(&ondisk, &self.output(), &name, &name)
     }
 
     pub fn pretty_info(&self) -> String {
@@ -235,6 +278,24 @@ impl MetaFile {
         } else {
             ret.push_str(&"unmodified".green().bold());
         }
+        if let Some(sourcefile) = &self.sourcefile {
+            ret.push_str(&format!("\nsource: {}", sourcefile));
+            if let Some(sourcecommit) = &self.sourcecommit {
+                ret.push_str(&format!(" (commit {})", sourcecommit));
+            }
+        }
         ret
     }
 }
+
+//TODO don't create metafiles for metafiles
+fn metafile_path(sourcepath: &Path) -> PathBuf {
+    let mut path = sourcepath.to_path_buf();
+    let filename = format!(
+        "{}.imosid.toml",
+        path.file_name().unwrap().to_string_lossy()
+    );
+    path.pop();
+    path.push(filename);
+    path
+}
@@ -0,0 +1,232 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use colored::{ColoredString, Colorize};
+use serde_json::{json, Value};
+
+use crate::dotwalker::walk_config_dir;
+use crate::files::DotFile;
+use crate::session::Session;
+
+/// how a section relates to its source
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    UpToDate,
+    /// upstream changed, the section can be updated
+    UpstreamNewer,
+    /// the user changed the section, upstream did not
+    Modified,
+    /// both the user and upstream changed the section
+    Diverged,
+    /// the source could not be read
+    Unknown,
+    /// command sources are only run by update
+    Skipped,
+}
+
+impl Freshness {
+    pub fn classify(modified: bool, upstream_changed: bool) -> Freshness {
+        match (modified, upstream_changed) {
+            (false, false) => Freshness::UpToDate,
+            (false, true) => Freshness::UpstreamNewer,
+            (true, false) => Freshness::Modified,
+            (true, true) => Freshness::Diverged,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Freshness::UpToDate => "up-to-date",
+            Freshness::UpstreamNewer => "upstream-newer",
+            Freshness::Modified => "modified",
+            Freshness::Diverged => "diverged",
+            Freshness::Unknown => "unknown",
+            Freshness::Skipped => "skipped",
+        }
+    }
+
+    fn colored(&self) -> ColoredString {
+        match self {
+            Freshness::UpToDate => self.as_str().green(),
+            Freshness::UpstreamNewer => self.as_str().yellow().bold(),
+            Freshness::Modified => self.as_str().blue(),
+            Freshness::Diverged => self.as_str().red().bold(),
+            Freshness::Unknown => self.as_str().red(),
+            Freshness::Skipped => self.as_str().dimmed(),
+        }
+    }
+}
+
+/// state of one section, or of a whole file managed by a metafile
+pub struct SectionStatus {
+    pub file: String,
+    pub section: Option<String>, // None for files managed by a metafile
+    pub source: String,
+    pub freshness: Freshness,
+    pub message: Option<String>, // why the source could not be read
+}
+
+impl SectionStatus {
+    fn json(&self) -> Value {
+        json!({
+            "file": self.file,
+            "section": self.section,
+            "source": self.source,
+            "status": self.freshness.as_str(),
+            "message": self.message,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutdatedFormat {
+    Table,
+    Json,
+}
+
+impl From<&str> for OutdatedFormat {
+    fn from(format: &str) -> Self {
+        match format {
+            "json" => OutdatedFormat::Json,
+            _ => OutdatedFormat::Table,
+        }
+    }
+}
+
+/// state of all sections with a source in a file or directory
+/// nothing is written
+pub struct OutdatedReport {
+    pub sections: Vec<SectionStatus>,
+}
+
+impl OutdatedReport {
+    pub fn new(path: &PathBuf, offline: bool, session: &Session) -> OutdatedReport {
+        let mut report = OutdatedReport {
+            sections: Vec::new(),
+        };
+        if path.is_dir() {
+            for entry in walk_config_dir(path) {
+                report.check_file(entry.path(), offline, session);
+            }
+        } else {
+            report.check_file(path, offline, session);
+        }
+        report
+    }
+
+    fn check_file(&mut self, path: &Path, offline: bool, session: &Session) {
+        match DotFile::from_pathbuf(&path.to_path_buf()) {
+            Ok(dotfile) => {
+                for mut status in dotfile.outdated(offline, session) {
+                    // report paths the way the user passed them in
+                    status.file = path.to_string_lossy().to_string();
+                    self.sections.push(status);
+                }
+            }
+            // binary files cannot contain markers
+            Err(e) if e.kind() == ErrorKind::InvalidData => {}
+            Err(e) => eprintln!("could not read {}: {}", path.display(), e),
+        }
+    }
+
+    pub fn count(&self, freshness: Freshness) -> usize {
+        self.sections
+            .iter()
+            .filter(|status| status.freshness == freshness)
+            .count()
+    }
+
+    /// 0 if nothing can be updated, 1 if updates are available,
+    /// 2 if a source could not be checked, skipped sources do not count
+    pub fn exit_code(&self) -> i32 {
+        if self.count(Freshness::Unknown) > 0 {
+            2
+        } else if self.count(Freshness::UpstreamNewer) + self.count(Freshness::Diverged) > 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn output(&self, format: OutdatedFormat) -> String {
+        match format {
+            OutdatedFormat::Table => self.table(),
+            OutdatedFormat::Json => {
+                let sections = self
+                    .sections
+                    .iter()
+                    .map(SectionStatus::json)
+                    .collect::<Vec<Value>>();
+                format!("{}\n", json!({ "sections": sections }))
+            }
+        }
+    }
+
+    fn table(&self) -> String {
+        let rows = self
+            .sections
+            .iter()
+            .map(|status| {
+                [
+                    status.file.clone(),
+                    status.section.clone().unwrap_or(String::from("*")),
+                    status.freshness.as_str().to_string(),
+                    match &status.message {
+                        Some(message) => format!("{} ({})", status.source, message),
+                        None => status.source.clone(),
+                    },
+                ]
+            })
+            .collect::<Vec<[String; 4]>>();
+        let header = [
+            String::from("FILE"),
+            String::from("SECTION"),
+            String::from("STATUS"),
+            String::from("SOURCE"),
+        ];
+        let mut widths = [0; 3];
+        for row in rows.iter().chain([&header]) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let mut ret = format!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+            header[0],
+            header[1],
+            header[2],
+            header[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        )
+        .bold()
+        .to_string();
+        ret.push('\n');
+        for (row, status) in rows.iter().zip(&self.sections) {
+            // pad before coloring, escape codes would count as width
+            let padding = " ".repeat(widths[2] - row[2].chars().count());
+            ret.push_str(&format!(
+                "{:<w0$}  {:<w1$}  {}{}  {}\n",
+                row[0],
+                row[1],
+                status.freshness.colored(),
+                padding,
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1]
+            ));
+        }
+        ret.push_str(&format!(
+            "{} up to date, {} upstream newer, {} modified, {} diverged, {} unknown, {} skipped\n",
+            self.count(Freshness::UpToDate),
+            self.count(Freshness::UpstreamNewer),
+            self.count(Freshness::Modified),
+            self.count(Freshness::Diverged),
+            self.count(Freshness::Unknown),
+            self.count(Freshness::Skipped)
+        ));
+        ret
+    }
+}
@@ -1,5 +1,5 @@
 // use crate::comment;
-use crate::comment::CommentType;
+use crate::comment::{CommentOptions, CommentSyntax, CommentType, InfoComment};
 use crate::commentmap::CommentMap;
 use crate::{
     comment::Specialcomment,
@@ -16,34 +16,86 @@ pub enum Section {
     Anonymous(SectionData),
 }
 
+/// how marker comments of a section are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarkerStyle {
+    /// one line per comment
+    Expanded,
+    /// info comments combined into the begin marker
+    Compact,
+}
+
 #[derive(Clone)]
 pub struct NamedSectionData {
-    pub name: String,           // section name, None if anonymous
-    pub source: Option<String>, // source to update section from
-    pub hash: String,           // current hash of section
-    pub targethash: String,     // hash section should have if unmodified
+    pub name: String,                   // section name, None if anonymous
+    pub source: Option<String>,         // source to update section from
+    pub source_options: CommentOptions, // key=value options of the source comment
+    pub hash: String,                   // current hash of section
+    pub targethash: String,             // hash section should have if unmodified
+    pub legacyhash: String,             // hash as computed by imosid before exact hashing
+    pub raw: Option<String>,            // exact text of the section as parsed, None once changed
+    pub children: Vec<Section>,         // nested sections and the text between them, empty if none
+}
+
+impl NamedSectionData {
+    /// a section is unmodified if its marker hash matches the exact content
+    /// or the hash an older imosid would have written for the same content
+    pub fn is_modified(&self) -> bool {
+        self.targethash != self.hash && self.targethash != self.legacyhash
+    }
+
+    /// commit a git source was last read from
+    pub fn source_commit(&self) -> Option<&str> {
+        self.source_option("commit")
+    }
+
+    /// sha256 the content of the source has to have
+    pub fn source_pin(&self) -> Option<&str> {
+        self.source_option("sha256")
+    }
+
+    fn source_option(&self, name: &str) -> Option<&str> {
+        self.source_options
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 #[derive(Clone)]
 pub struct SectionData {
     pub startline: u32, // line number section starts at in file
+    /// content of named sections is stored relative to the marker indentation,
+    /// so the hash stays the same wherever the section is indented
     pub content: String,
-    pub endline: u32, // line number section ends at in file
+    pub endline: u32,   // line number section ends at in file
+    pub indent: String, // whitespace in front of the begin marker
+    /// whether the indentation was stripped from each line of the content,
+    /// lines without it are written back as they were. empty if not known
+    pub indented: Vec<bool>,
 }
 
 impl Hashable for Section {
     /// set target hash to current hash
     /// marking the section as unmodified
     /// return false if nothing has changed
-
     fn compile(&mut self) -> ChangeState {
         match self {
             Section::Named(_, named_data) => {
-                if named_data.targethash == named_data.hash {
-                    ChangeState::Unchanged
-                } else {
+                let mut changed = false;
+                for child in named_data.children.iter_mut() {
+                    changed = child.compile().into() || changed;
+                }
+                if named_data.targethash != named_data.hash {
+                    // also rewrites legacy hashes to exact content hashes
                     named_data.targethash = named_data.hash.clone();
+                    named_data.raw = None;
+                    changed = true;
+                }
+                if changed {
                     ChangeState::Changed
+                } else {
+                    ChangeState::Unchanged
                 }
             }
             Section::Anonymous(_) => ChangeState::Unchanged,
@@ -52,9 +104,14 @@ impl Hashable for Section {
 
     /// generate section hash
     /// and detect section status
+    /// the hash covers the exact content including line endings
     fn finalize(&mut self) {
         if let Section::Named(data, named_data) = self {
+            if !named_data.children.is_empty() {
+                data.content = nested_content(&data.indent, &named_data.children);
+            }
             named_data.hash = digest(data.content.as_str()).to_uppercase();
+            named_data.legacyhash = legacy_digest(&data.content);
         }
     }
 }
@@ -72,27 +129,43 @@ impl Section {
                 startline: start,
                 content: String::from(""),
                 endline: end,
+                indent: String::new(),
+                indented: Vec::new(),
             },
             NamedSectionData {
                 name,
                 source,
+                source_options: Vec::new(),
                 hash: String::from(""),
                 targethash,
+                legacyhash: String::from(""),
+                raw: None,
+                children: Vec::new(),
             },
         )
     }
 
     pub fn from_comment_map(name: &str, map: &CommentMap) -> Option<Section> {
-        Some(Section::new(
+        let source = map.get_comment(name, CommentType::SourceInfo);
+        let mut section = Section::new(
             map.get_comment(name, CommentType::SectionBegin)?.line,
             map.get_comment(name, CommentType::SectionEnd)?.line,
             name.to_string(),
-            map.get_comment(name, CommentType::SourceInfo)
-                .and_then(|source| source.clone().argument),
+            source.and_then(|source| source.clone().argument),
             map.get_comment(name, CommentType::HashInfo)?
                 .clone()
                 .argument?,
-        ))
+        );
+        if let (Section::Named(_, named_data), Some(source)) = (&mut section, source) {
+            named_data.source_options = source.options.clone();
+        }
+        if let Section::Named(data, _) = &mut section {
+            data.indent = map
+                .get_comment(name, CommentType::SectionBegin)?
+                .indent
+                .clone();
+        }
+        Some(section)
     }
 
     pub fn new_anonymous(start: u32, end: u32) -> Section {
@@ -100,59 +173,255 @@ impl Section {
             startline: start,
             content: String::from(""),
             endline: end,
+            indent: String::new(),
+            indented: Vec::new(),
         })
     }
 
     /// append string to content
+    /// lines are expected to carry their own line ending
+    /// named sections strip the marker indentation from each line
     //maybe make this a trait?
     pub fn push_str(&mut self, line: &str) {
         match self {
-            Section::Named(data, _) => data,
-            Section::Anonymous(data) => data,
+            Section::Named(data, _) => {
+                let indent = data.indent.clone();
+                push_unindented(data, line, &indent);
+            }
+            Section::Anonymous(data) => data.content.push_str(line),
+        }
+    }
+
+    /// append lines with the indentation of the section they are nested in stripped
+    pub fn push_nested(&mut self, line: &str, indent: &str) {
+        match self {
+            Section::Named(data, _) | Section::Anonymous(data) => {
+                push_unindented(data, line, indent)
+            }
         }
-        .content
-        .push_str(line)
     }
 
     /// return entire section with formatted marker comments and content
-    pub fn output(&self, commentsign: &str) -> String {
+    /// sections which have not changed since parsing are returned verbatim
+    pub fn output(&self, commentsign: &CommentSyntax, style: MarkerStyle) -> String {
         match self {
+            Section::Named(_, NamedSectionData { raw: Some(raw), .. }) if self.is_pristine() => {
+                raw.clone()
+            }
             Section::Named(data, named_data) => {
-                let mut outstr = String::new();
-                outstr.push_str(&Specialcomment::new_string(
-                    commentsign,
-                    CommentType::SectionBegin,
-                    &named_data.name,
-                    None,
-                ));
-                outstr.push_str(&Specialcomment::new_string(
-                    commentsign,
-                    CommentType::HashInfo,
-                    &named_data.name,
-                    Some(&named_data.targethash),
-                ));
+                let newline = line_ending(&data.content);
+                let mut infos: Vec<InfoComment> =
+                    vec![(CommentType::HashInfo, &named_data.targethash, &[])];
                 if let Some(source) = named_data.source.as_ref() {
-                    outstr.push_str(&Specialcomment::new_string(
-                        commentsign,
-                        CommentType::SourceInfo,
-                        &named_data.name,
-                        Some(source),
-                    ));
+                    infos.push((CommentType::SourceInfo, source, &named_data.source_options));
                 }
                 //TODO: section target
-                outstr.push_str(&data.content);
+
+                let mut outstr = String::new();
+                match style {
+                    MarkerStyle::Compact => {
+                        outstr.push_str(&data.indent);
+                        outstr.push_str(&Specialcomment::new_combined_string(
+                            commentsign,
+                            CommentType::SectionBegin,
+                            &named_data.name,
+                            &infos,
+                        ));
+                        outstr.push_str(newline);
+                    }
+                    MarkerStyle::Expanded => {
+                        outstr.push_str(&data.indent);
+                        outstr.push_str(&Specialcomment::new_string(
+                            commentsign,
+                            CommentType::SectionBegin,
+                            &named_data.name,
+                            None,
+                            &[],
+                        ));
+                        outstr.push_str(newline);
+                        for (ctype, argument, options) in infos {
+                            outstr.push_str(&data.indent);
+                            outstr.push_str(&Specialcomment::new_string(
+                                commentsign,
+                                ctype,
+                                &named_data.name,
+                                Some(argument),
+                                options,
+                            ));
+                            outstr.push_str(newline);
+                        }
+                    }
+                }
+                if named_data.children.is_empty() {
+                    outstr.push_str(&indent_content(&data.indent, data));
+                    if !data.content.is_empty() && !data.content.ends_with('\n') {
+                        outstr.push_str(newline);
+                    }
+                }
+                for child in &named_data.children {
+                    match child {
+                        Section::Anonymous(childdata) => {
+                            outstr.push_str(&indent_content(&data.indent, childdata))
+                        }
+                        Section::Named(..) => outstr.push_str(&child.output(commentsign, style)),
+                    }
+                }
+                outstr.push_str(&data.indent);
                 outstr.push_str(&Specialcomment::new_string(
                     commentsign,
                     CommentType::SectionEnd,
                     &named_data.name,
                     None,
+                    &[],
                 ));
+                outstr.push_str(newline);
                 outstr
             }
             Section::Anonymous(data) => data.content.clone(),
         }
     }
 
+    /// true if neither this section nor a nested one changed since parsing
+    fn is_pristine(&self) -> bool {
+        match self {
+            Section::Named(_, named_data) => {
+                named_data.raw.is_some() && named_data.children.iter().all(Section::is_pristine)
+            }
+            Section::Anonymous(_) => true,
+        }
+    }
+
+    /// regenerate all markers on output, including those of nested sections
+    pub fn forget_raw(&mut self) {
+        if let Section::Named(_, named_data) = self {
+            named_data.raw = None;
+            for child in named_data.children.iter_mut() {
+                child.forget_raw();
+            }
+        }
+    }
+
+    /// true if this section or a section nested in it is modified
+    pub fn is_modified(&self) -> bool {
+        match self {
+            Section::Named(_, named_data) => {
+                named_data.is_modified() || named_data.children.iter().any(Section::is_modified)
+            }
+            Section::Anonymous(_) => false,
+        }
+    }
+
+    /// this section and all sections nested in it, parents first
+    pub fn named_sections(&self) -> Vec<(&SectionData, &NamedSectionData)> {
+        match self {
+            Section::Named(data, named_data) => {
+                let mut ret = vec![(data, named_data)];
+                for child in &named_data.children {
+                    ret.extend(child.named_sections());
+                }
+                ret
+            }
+            Section::Anonymous(_) => Vec::new(),
+        }
+    }
+
+    /// find this or a nested section by name
+    pub fn find(&self, name: &str) -> Option<&Section> {
+        let Section::Named(_, named_data) = self else {
+            return None;
+        };
+        if named_data.name == name {
+            return Some(self);
+        }
+        named_data
+            .children
+            .iter()
+            .find_map(|child| child.find(name))
+    }
+
+    /// replace the nested section with the same name,
+    /// the new section takes over the indentation of the old one
+    pub fn replace_nested(&mut self, mut section: Section) -> bool {
+        let Section::Named(_, named_data) = self else {
+            return false;
+        };
+        let Section::Named(_, new_named_data) = &section else {
+            return false;
+        };
+        let name = new_named_data.name.clone();
+        let position = named_data.children.iter().position(
+            |child| matches!(child, Section::Named(_, child_named) if child_named.name == name),
+        );
+        let replaced = match position {
+            Some(index) => {
+                section.set_indent(&named_data.children[index].get_data().indent.clone());
+                named_data.children[index] = section;
+                true
+            }
+            None => named_data
+                .children
+                .iter_mut()
+                .any(|child| child.replace_nested(section.clone())),
+        };
+        if replaced {
+            named_data.raw = None;
+        }
+        replaced
+    }
+
+    /// remove a section nested somewhere in this one
+    pub fn remove_nested(&mut self, name: &str) -> bool {
+        let Section::Named(_, named_data) = self else {
+            return false;
+        };
+        let position = named_data.children.iter().position(
+            |child| matches!(child, Section::Named(_, child_named) if child_named.name == name),
+        );
+        let removed = match position {
+            Some(index) => {
+                named_data.children.remove(index);
+                true
+            }
+            None => named_data
+                .children
+                .iter_mut()
+                .any(|child| child.remove_nested(name)),
+        };
+        if removed {
+            named_data.raw = None;
+            self.finalize();
+        }
+        removed
+    }
+
+    /// move the section to another indentation,
+    /// nested sections keep their indentation relative to it
+    /// all lines are indented like new content then
+    pub fn set_indent(&mut self, indent: &str) {
+        let Section::Named(data, named_data) = self else {
+            return;
+        };
+        if data.indent == indent {
+            return;
+        }
+        for child in named_data.children.iter_mut() {
+            match child {
+                Section::Named(childdata, _) => {
+                    let relative = childdata
+                        .indent
+                        .strip_prefix(data.indent.as_str())
+                        .unwrap_or(&childdata.indent);
+                    let childindent = format!("{}{}", indent, relative);
+                    child.set_indent(&childindent);
+                }
+                Section::Anonymous(childdata) => childdata.indented.clear(),
+            }
+        }
+        data.indent = String::from(indent);
+        data.indented.clear();
+        named_data.raw = None;
+    }
+
     pub fn get_data(&self) -> &SectionData {
         match self {
             Section::Named(data, _) => data,
@@ -163,22 +432,134 @@ impl Section {
     pub fn pretty_info(&self) -> Option<String> {
         match self {
             Section::Anonymous(_) => None,
-            Section::Named(data, named_data) => Some(format!(
-                "{}-{}: {} | {}{}",
-                &data.startline,
-                &data.endline,
-                &named_data.name,
-                if named_data.targethash == named_data.hash {
-                    "ok".bold().green()
-                } else {
-                    "modified".bold().red()
-                },
-                if let Some(source) = &named_data.source {
-                    format!(" | source {}", source)
-                } else {
-                    String::new()
+            Section::Named(data, named_data) => {
+                let mut info = format!(
+                    "{}-{}: {} | {}{}",
+                    &data.startline,
+                    &data.endline,
+                    &named_data.name,
+                    if named_data.is_modified() {
+                        "modified".bold().red()
+                    } else {
+                        "ok".bold().green()
+                    },
+                    if let Some(source) = &named_data.source {
+                        match named_data.source_commit() {
+                            Some(commit) => format!(" | source {} (commit {})", source, commit),
+                            None => format!(" | source {}", source),
+                        }
+                    } else {
+                        String::new()
+                    }
+                );
+                // nested sections are listed below their parent
+                for child in &named_data.children {
+                    if let Some(childinfo) = child.pretty_info() {
+                        for line in childinfo.lines() {
+                            info.push_str("\n  ");
+                            info.push_str(line);
+                        }
+                    }
                 }
+                Some(info)
+            }
+        }
+    }
+}
+
+/// content of a section with nested sections, every nested section
+/// is replaced by its relative indentation and name, like `  ... name`
+/// so changes inside of nested sections do not modify the parent
+fn nested_content(indent: &str, children: &[Section]) -> String {
+    let mut content = String::new();
+    for child in children {
+        match child {
+            Section::Anonymous(data) => content.push_str(&data.content),
+            Section::Named(data, named_data) => content.push_str(&format!(
+                "{}... {}\n",
+                data.indent.strip_prefix(indent).unwrap_or(&data.indent),
+                named_data.name
             )),
         }
     }
+    content
+}
+
+/// content of a section with every nested section expanded to its
+/// own content, indented like in the file but without its markers
+pub fn expanded_content(data: &SectionData, named_data: &NamedSectionData) -> String {
+    if named_data.children.is_empty() {
+        return data.content.clone();
+    }
+    let mut content = String::new();
+    for child in &named_data.children {
+        match child {
+            Section::Anonymous(childdata) => content.push_str(&childdata.content),
+            Section::Named(childdata, child_named_data) => {
+                let relative = childdata
+                    .indent
+                    .strip_prefix(data.indent.as_str())
+                    .unwrap_or(&childdata.indent);
+                content.push_str(&if child_named_data.children.is_empty() {
+                    indent_content(relative, childdata)
+                } else {
+                    indent_lines(relative, &expanded_content(childdata, child_named_data))
+                });
+            }
+        }
+    }
+    content
+}
+
+// strip the indentation from every line and remember which lines had it
+fn push_unindented(data: &mut SectionData, text: &str, indent: &str) {
+    for line in text.split_inclusive('\n') {
+        let stripped = line.strip_prefix(indent);
+        data.indented.push(stripped.is_some());
+        data.content.push_str(stripped.unwrap_or(line));
+    }
+}
+
+/// put indentation back in front of the lines it was stripped from,
+/// content whose lines are not known is indented like indent_lines does
+fn indent_content(indent: &str, data: &SectionData) -> String {
+    let lines = data.content.split_inclusive('\n');
+    if data.indented.len() != lines.clone().count() {
+        return indent_lines(indent, &data.content);
+    }
+    let mut ret = String::new();
+    for (line, indented) in lines.zip(&data.indented) {
+        if *indented {
+            ret.push_str(indent);
+        }
+        ret.push_str(line);
+    }
+    ret
+}
+
+/// put indentation in front of every line that is not blank
+fn indent_lines(indent: &str, content: &str) -> String {
+    let mut ret = String::new();
+    for line in content.split_inclusive('\n') {
+        if !line.trim_end_matches(['\r', '\n']).is_empty() {
+            ret.push_str(indent);
+        }
+        ret.push_str(line);
+    }
+    ret
+}
+
+/// hash of the content with all line endings removed
+/// this is what markers written before exact hashing contain
+pub fn legacy_digest(content: &str) -> String {
+    digest(content.lines().collect::<String>()).to_uppercase()
+}
+
+/// line ending used by the content, defaults to unix line endings
+pub fn line_ending(content: &str) -> &'static str {
+    if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    }
 }
@@ -0,0 +1,56 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backup::{run_command, BackupSet, Backups, RetentionPolicy};
+use crate::cache::Cache;
+use crate::journal::Journal;
+use crate::store::ObjectStore;
+use crate::xdg;
+
+/// the places imosid keeps its own files in
+pub struct Session {
+    pub cache: Cache,
+    pub store: ObjectStore,
+    pub backups: Backups,
+    pub journal: Journal,
+    pub retention: RetentionPolicy,
+    current: Option<BackupSet>, // backup set of this run, created once the first file is changed
+}
+
+impl Session {
+    /// cache holds fetched sources, data the object store,
+    /// state the backups and the journal
+    pub fn new(cache: PathBuf, data: PathBuf, state: PathBuf) -> Session {
+        Session {
+            cache: Cache::at(cache),
+            store: ObjectStore::at(data.join("objects")),
+            backups: Backups::at(state.join("backups")),
+            journal: Journal::at(state.join("journal.jsonl")),
+            retention: RetentionPolicy::default(),
+            current: None,
+        }
+    }
+
+    /// use the base directories and the backup policy of the user
+    pub fn open() -> Session {
+        Session {
+            retention: RetentionPolicy::load(),
+            ..Session::new(
+                xdg::cache_home().join("imosid"),
+                xdg::data_home().join("imosid"),
+                xdg::state_home().join("imosid"),
+            )
+        }
+    }
+
+    /// back up a file before it is changed, once per run
+    pub fn snapshot(&mut self, path: &Path) -> io::Result<()> {
+        if self.current.is_none() {
+            self.current = Some(self.backups.create(&run_command())?);
+            if let Err(e) = self.backups.prune(&self.retention) {
+                eprintln!("could not remove old backups: {}", e);
+            }
+        }
+        self.current.as_mut().unwrap().add(path)
+    }
+}
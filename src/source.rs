@@ -0,0 +1,404 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use colored::Colorize;
+
+use crate::cache::CachedResponse;
+use crate::comment::{quote, tokenize};
+use crate::files::expand_tilde;
+use crate::session::Session;
+
+// give up on servers that do not answer instead of hanging the update
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// where the content of a section comes from
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SourceLocation {
+    File(PathBuf),
+    /// file at a commit, tag or branch of a local repository, bare or not
+    Git {
+        repository: PathBuf,
+        reference: String,
+        path: String,
+    },
+    /// http or https url, fetched into the cache
+    Http(String),
+    /// program and arguments whose output is the content, run by update
+    Command(Vec<String>),
+}
+
+/// content read from a source
+pub struct SourceContent {
+    pub text: String,
+    pub revision: Option<String>, // resolved commit id for git sources
+}
+
+/// parsed argument of a source comment, for example
+/// `../common/shell.sh#aliases`, `file:///usr/share/instantdotfiles/zshrc#prompt`
+/// `git+file:///path/to/repo?ref=v1.2#path/to/file:section`
+/// `https://example.com/dotfiles/zshrc#prompt` or `"cmd:dircolors -p"`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceUri {
+    pub location: SourceLocation,
+    pub section: Option<String>, // section to take, defaults to the name of the section
+}
+
+impl SourceUri {
+    /// parse a source, relative paths are resolved against base,
+    /// the directory of the file that declares the source
+    pub fn parse(source: &str, base: &Path) -> Result<SourceUri, String> {
+        // the whole output of a command is the content, there are no sections to pick
+        if let Some(command) = source.strip_prefix("cmd:") {
+            let command = tokenize(command)?
+                .into_iter()
+                .map(|token| match token.key {
+                    Some(key) => format!("{}={}", key, token.value),
+                    None => token.value,
+                })
+                .collect::<Vec<String>>();
+            if command.is_empty() {
+                return Err(String::from("command source has no command"));
+            }
+            return Ok(SourceUri {
+                location: SourceLocation::Command(command),
+                section: None,
+            });
+        }
+
+        let (path, section) = match source.rsplit_once('#') {
+            Some((_, "")) => return Err(format!("empty section name in source {}", source)),
+            Some((path, section)) => (path, Some(String::from(section))),
+            None => (source, None),
+        };
+        if path.is_empty() {
+            return Err(String::from("source has no path"));
+        }
+
+        if let Some(uri) = path.strip_prefix("git+") {
+            return SourceUri::parse_git(uri, section.as_deref());
+        }
+
+        let location = if let Some(uripath) = path.strip_prefix("file://") {
+            if !uripath.starts_with('/') {
+                return Err(format!(
+                    "file uri {} has to be absolute, like file:///path",
+                    source
+                ));
+            }
+            SourceLocation::File(PathBuf::from(percent_decode(uripath)?))
+        } else if path.starts_with("http://") || path.starts_with("https://") {
+            SourceLocation::Http(String::from(path))
+        } else if let Some((scheme, _)) = path.split_once("://") {
+            return Err(format!("unsupported source scheme {}", scheme));
+        } else {
+            SourceLocation::File(base.join(expand_tilde(path)))
+        };
+
+        Ok(SourceUri { location, section })
+    }
+
+    // the fragment of git sources holds the path in the repository
+    // and optionally the section, separated by a colon
+    fn parse_git(uri: &str, fragment: Option<&str>) -> Result<SourceUri, String> {
+        let Some(uripath) = uri.strip_prefix("file://") else {
+            return Err(String::from(
+                "only local git repositories are supported, use git+file:///path/to/repo",
+            ));
+        };
+        let (repository, query) = match uripath.split_once('?') {
+            Some((repository, query)) => (repository, Some(query)),
+            None => (uripath, None),
+        };
+        if !repository.starts_with('/') {
+            return Err(format!("git repository {} has to be absolute", repository));
+        }
+
+        let mut reference = String::from("HEAD");
+        for parameter in query.into_iter().flat_map(|query| query.split('&')) {
+            match parameter.split_once('=') {
+                // references starting with a dash would be read as options by git
+                Some(("ref", value)) if !value.is_empty() && !value.starts_with('-') => {
+                    reference = percent_decode(value)?
+                }
+                _ => return Err(format!("unknown git source parameter {}", parameter)),
+            }
+        }
+
+        let Some(fragment) = fragment else {
+            return Err(String::from(
+                "git source needs a file, like #path/to/file or #path/to/file:section",
+            ));
+        };
+        let (path, section) = match fragment.rsplit_once(':') {
+            Some((path, section)) if !section.is_empty() => (path, Some(String::from(section))),
+            _ => (fragment, None),
+        };
+
+        Ok(SourceUri {
+            location: SourceLocation::Git {
+                repository: PathBuf::from(percent_decode(repository)?),
+                reference,
+                path: String::from(path.trim_start_matches('/')),
+            },
+            section,
+        })
+    }
+
+    /// read the content of the source, offline only uses cached copies of remote sources
+    /// a dry run leaves the cache as it is
+    pub fn read(
+        &self,
+        offline: bool,
+        dry_run: bool,
+        session: &Session,
+    ) -> Result<SourceContent, String> {
+        match &self.location {
+            SourceLocation::File(path) => fs::read_to_string(path)
+                .map(|text| SourceContent {
+                    text,
+                    revision: None,
+                })
+                .map_err(|e| format!("could not read {}: {}", path.display(), e)),
+            SourceLocation::Git {
+                repository,
+                reference,
+                path,
+            } => {
+                let commit = git(
+                    repository,
+                    &[
+                        "rev-parse",
+                        "--verify",
+                        "--quiet",
+                        &format!("{}^{{commit}}", reference),
+                    ],
+                )
+                .map_err(|_| format!("could not find {} in {}", reference, repository.display()))?;
+                let commit = String::from(commit.trim());
+                let text = git(
+                    repository,
+                    &["cat-file", "blob", &format!("{}:{}", commit, path)],
+                )?;
+                Ok(SourceContent {
+                    text,
+                    revision: Some(commit),
+                })
+            }
+            SourceLocation::Http(url) => {
+                fetch(url, offline, dry_run, session).map(|text| SourceContent {
+                    text,
+                    revision: None,
+                })
+            }
+            SourceLocation::Command(_) => {
+                Err(String::from("command sources are only run by update"))
+            }
+        }
+    }
+
+    /// name of the file the source points to, used to detect its comment syntax
+    pub fn filename(&self) -> String {
+        match &self.location {
+            SourceLocation::File(path) => path.to_string_lossy().to_string(),
+            SourceLocation::Git { path, .. } => path.clone(),
+            SourceLocation::Http(url) => {
+                let path = url.split(['?', '#']).next().unwrap_or(url);
+                String::from(path.rsplit('/').next().unwrap_or(path))
+            }
+            SourceLocation::Command(command) => command[0].clone(),
+        }
+    }
+
+    /// the section to take from the source
+    pub fn section_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.section.as_deref().unwrap_or(name)
+    }
+
+    /// check that the source exists, without reading it
+    pub fn check(&self) -> Result<(), String> {
+        match &self.location {
+            SourceLocation::File(path) => {
+                if path.is_file() {
+                    Ok(())
+                } else {
+                    Err(format!("source file {} does not exist", path.display()))
+                }
+            }
+            SourceLocation::Git { repository, .. } => {
+                if repository.is_dir() {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "git repository {} does not exist",
+                        repository.display()
+                    ))
+                }
+            }
+            // remote sources are only checked when fetched, commands when run
+            SourceLocation::Http(_) | SourceLocation::Command(_) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for SourceUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            SourceLocation::File(path) => {
+                write!(f, "{}", path.display())?;
+                if let Some(section) = &self.section {
+                    write!(f, "#{}", section)?;
+                }
+            }
+            SourceLocation::Git {
+                repository,
+                reference,
+                path,
+            } => {
+                write!(f, "{} {}:{}", repository.display(), reference, path)?;
+                if let Some(section) = &self.section {
+                    write!(f, ":{}", section)?;
+                }
+            }
+            SourceLocation::Http(url) => {
+                write!(f, "{}", url)?;
+                if let Some(section) = &self.section {
+                    write!(f, "#{}", section)?;
+                }
+            }
+            SourceLocation::Command(command) => {
+                let command = command
+                    .iter()
+                    .map(|arg| quote(arg))
+                    .collect::<Vec<String>>();
+                write!(f, "cmd:{}", command.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// run git in a repository without touching any working tree
+fn git(repository: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repository)
+        .args(args)
+        .output()
+        .map_err(|e| format!("could not run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|_| String::from("git source is not valid utf-8"))
+}
+
+/// sha256 of source content, as written in sha256= pins
+///
+/// a pin of a section covers the text between its markers, relative to
+/// the marker indentation and with nested sections expanded to their
+/// content. a pin in a metafile covers the whole source file.
+pub fn content_digest(text: &str) -> String {
+    sha256::digest(text).to_lowercase()
+}
+
+/// true if the pin matches the content digest, case does not matter
+pub fn matches_pin(pin: &str, digest: &str) -> bool {
+    pin.eq_ignore_ascii_case(digest)
+}
+
+/// pins are 64 hex digits, in upper or lower case
+pub fn is_valid_pin(pin: &str) -> bool {
+    pin.len() == 64 && pin.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// fetch a remote source, revalidating the cached copy with the server
+// if the server cannot be reached the cached copy is used
+fn fetch(url: &str, offline: bool, dry_run: bool, session: &Session) -> Result<String, String> {
+    let cached = session.cache.load(url);
+    if offline {
+        return cached
+            .map(|cached| cached.body)
+            .ok_or_else(|| format!("{} is not cached, run without --offline", url));
+    }
+
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout(REQUEST_TIMEOUT)
+        .build();
+    let mut request = agent.get(url);
+    if let Some(cached) = &cached {
+        if let Some(etag) = &cached.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &cached.last_modified {
+            request = request.set("If-Modified-Since", last_modified);
+        }
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => {
+            return Err(format!("{} answered with status {}", url, status));
+        }
+        Err(e) => {
+            return match cached {
+                Some(cached) => {
+                    eprintln!("{} {}: {}", "using cached copy of".yellow(), url.bold(), e);
+                    Ok(cached.body)
+                }
+                None => Err(format!("could not fetch {}: {}", url, e)),
+            };
+        }
+    };
+
+    if response.status() == 304 {
+        if let Some(cached) = cached {
+            return Ok(cached.body);
+        }
+    }
+    let etag = response.header("ETag").map(String::from);
+    let last_modified = response.header("Last-Modified").map(String::from);
+    let body = response
+        .into_string()
+        .map_err(|e| format!("could not read {}: {}", url, e))?;
+    let fresh = CachedResponse {
+        body,
+        etag,
+        last_modified,
+    };
+    if dry_run {
+        return Ok(fresh.body);
+    }
+    if let Err(e) = session.cache.store(url, &fresh) {
+        eprintln!("could not cache {}: {}", url.yellow(), e);
+    }
+    Ok(fresh.body)
+}
+
+// decode %XX escapes in file uris
+fn percent_decode(text: &str) -> Result<String, String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let value = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| format!("invalid escape in {}", text))?;
+            bytes.push(value);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("invalid escape in {}", text))
+}
@@ -0,0 +1,145 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use sha256::digest;
+use toml::{Table, Value};
+
+use crate::atomicwrite::write_atomic;
+
+/// content addressed store of section and file contents, named after
+/// the same hashes the markers carry, so the content a section had
+/// when it was compiled can be found again after the user changed it
+pub struct ObjectStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn at(dir: PathBuf) -> ObjectStore {
+        ObjectStore { dir }
+    }
+
+    // objects are spread over directories named after the first two digits
+    fn path(&self, hash: &str) -> PathBuf {
+        let hash = hash.to_lowercase();
+        let (prefix, rest) = hash.split_at(2.min(hash.len()));
+        self.dir.join(prefix).join(rest)
+    }
+
+    /// store content, return its hash
+    /// objects that do not match their hash, like ones cut off by a crash,
+    /// are replaced
+    pub fn put(&self, content: &str) -> io::Result<String> {
+        let hash = digest(content).to_uppercase();
+        if self.get(&hash).is_none() {
+            let path = self.path(&hash);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_atomic(&path, content.as_bytes(), None)?;
+        }
+        Ok(hash)
+    }
+
+    /// content with the given hash, if it has been stored intact
+    pub fn get(&self, hash: &str) -> Option<String> {
+        if !is_hash(hash) {
+            return None;
+        }
+        let content = fs::read_to_string(self.path(hash)).ok()?;
+        if !digest(content.as_str()).eq_ignore_ascii_case(hash) {
+            eprintln!("stored content {} is damaged", hash);
+            return None;
+        }
+        Some(content)
+    }
+
+    /// hashes of all stored contents
+    pub fn hashes(&self) -> io::Result<Vec<String>> {
+        let mut hashes = Vec::new();
+        if !self.dir.is_dir() {
+            return Ok(hashes);
+        }
+        for prefix in fs::read_dir(&self.dir)? {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for object in fs::read_dir(prefix.path())? {
+                let hash = format!(
+                    "{}{}",
+                    prefix.file_name().to_string_lossy(),
+                    object?.file_name().to_string_lossy()
+                )
+                .to_uppercase();
+                // leave files alone that were not put there by imosid
+                if is_hash(&hash) {
+                    hashes.push(hash);
+                }
+            }
+        }
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    // the files whose contents are stored, next to the objects
+    fn managed_path(&self) -> PathBuf {
+        self.dir.with_file_name("managed.toml")
+    }
+
+    /// files written with stored contents, gc keeps what they refer to
+    pub fn managed(&self) -> io::Result<Vec<PathBuf>> {
+        let content = match fs::read_to_string(self.managed_path()) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let table = content
+            .parse::<Table>()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(match table.get("files") {
+            Some(Value::Array(files)) => files
+                .iter()
+                .filter_map(Value::as_str)
+                .map(PathBuf::from)
+                .collect(),
+            _ => Vec::new(),
+        })
+    }
+
+    /// remember a file that refers to stored contents
+    pub fn add_managed(&self, path: &Path) -> io::Result<()> {
+        let path = std::path::absolute(path)?;
+        let mut managed = self.managed()?;
+        if managed.contains(&path) {
+            return Ok(());
+        }
+        managed.push(path);
+        self.set_managed(&managed)
+    }
+
+    pub fn set_managed(&self, paths: &[PathBuf]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut table = Table::new();
+        let files = paths
+            .iter()
+            .map(|path| Value::String(path.to_string_lossy().to_string()))
+            .collect();
+        table.insert(String::from("files"), Value::Array(files));
+        write_atomic(&self.managed_path(), table.to_string().as_bytes(), None)
+    }
+
+    pub fn remove(&self, hash: &str) -> io::Result<()> {
+        let path = self.path(hash);
+        fs::remove_file(&path)?;
+        // the directory is recreated by the next put
+        if let Some(parent) = path.parent() {
+            let _ = fs::remove_dir(parent);
+        }
+        Ok(())
+    }
+}
+
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
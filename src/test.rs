@@ -1,8 +1,7 @@
 #[cfg(test)]
-
 mod tests {
 
-    pub const FILE_CONTENT: &'static str = "#!/bin/bash
+    pub const FILE_CONTENT: &str = "#!/bin/bash
 
 #... firstsection begin
 #... firstsection hash 1F5E86D1E173F1B671B5EF32216DFF07CF973A8A7BFAFAD0AFE84BB2F29FB6C5
@@ -16,15 +15,64 @@ echo \"content of the first section\"
 echo \"content of the second section\"
 #... secondsection end";
 
-    use crate::comment::{CommentType, Specialcomment};
-    use crate::files::DotFile;
+    use crate::atomicwrite::write_atomic;
+    use crate::backup::{Backups, RetentionPolicy};
+    use crate::command::CommandPolicy;
+    use crate::comment::{quote, tokenize, CommentSyntax, CommentType, Specialcomment};
+    use crate::diff::{labeled_diff, unified_diff};
+    use crate::files::{ApplyResult, ConflictStyle, DotFile, UpdateOptions};
     use crate::hashable::Hashable;
-    use crate::section::Section;
+    use crate::journal::{display_path, JournalEntry, LogFilter};
+    use crate::languages::{LanguageRegistry, SyntaxRule};
+    use crate::lint::{LintFormat, LintReport};
+    use crate::merge::merge;
+    use crate::metafile::MetaFile;
+    use crate::outdated::{Freshness, OutdatedFormat, OutdatedReport};
+    use crate::section::{MarkerStyle, Section};
+    use crate::session::Session;
+    use crate::source::{content_digest, SourceLocation, SourceUri};
+    use crate::timestamp::parse_since;
+    use crate::transaction::ApplyTransaction;
 
-    use std::fs::File;
-    use std::io::Write;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::{Path, PathBuf};
     use tempdir::TempDir;
 
+    // temporary directory for the files of one test
+    struct Fixture {
+        dir: TempDir,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            Fixture {
+                dir: TempDir::new("imosidtest").unwrap(),
+            }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        // write a file below the directory, along with its parents
+        fn write(&self, name: &str, content: impl AsRef<[u8]>) -> PathBuf {
+            let path = self.path(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+
+        // directories for the own files of imosid, like the cache
+        fn session(&self) -> Session {
+            Session::new(self.path("cache"), self.path("data"), self.path("state"))
+        }
+
+        fn dotfile(&self, name: &str, content: impl AsRef<[u8]>) -> DotFile {
+            DotFile::from_pathbuf(&self.write(name, content)).unwrap()
+        }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
@@ -32,15 +80,22 @@ echo \"content of the second section\"
 
     #[test]
     fn test_comment() {
-        let comment = Specialcomment::from_line("#...tester begin", "#", 20).unwrap();
+        let comment = Specialcomment::from_line("#...tester begin", &CommentSyntax::line("#"), 20)
+            .unwrap()
+            .remove(0);
         assert_eq!(comment.line, 20);
         assert_eq!(comment.section.as_str(), "tester");
     }
 
     #[test]
     fn test_comment_argument() {
-        let comment =
-            Specialcomment::from_line("#...helloworold hash abcdefghijk", "#", 21).unwrap();
+        let comment = Specialcomment::from_line(
+            "#...helloworold hash abcdefghijk",
+            &CommentSyntax::line("#"),
+            21,
+        )
+        .unwrap()
+        .remove(0);
         assert_eq!(comment.line, 21);
         assert_eq!(comment.comment_type, CommentType::HashInfo);
         assert_eq!(comment.section.as_str(), "helloworold");
@@ -57,33 +112,1888 @@ testing123
 ";
 
         let mut testsection = Section::new(1, 10, "test".to_string(), None, "adsasd".to_string());
-        testsection.push_str("hello world");
-        testsection.push_str("testing123");
+        testsection.push_str("hello world\n");
+        testsection.push_str("testing123\n");
         testsection.finalize();
         testsection.compile();
         if let Section::Named(_, named_data) = &testsection {
             assert_eq!(named_data.name.as_str(), "test");
         }
-        assert_eq!(testsection.output(&"#").as_str(), sectiontarget);
+        assert_eq!(
+            testsection
+                .output(&CommentSyntax::line("#"), MarkerStyle::Expanded)
+                .as_str(),
+            sectiontarget
+        );
     }
 
     #[test]
     fn testfile() {
-        let tmp_dir = TempDir::new("imosidtest").unwrap();
-        let testpath = tmp_dir.path().join("testfile.sh");
-        let mut testfile = File::create(&testpath).unwrap();
-        testfile.write_all(FILE_CONTENT.as_bytes()).unwrap();
-
-        let testfile = DotFile::from_pathbuf(&testpath).unwrap();
+        let testfile = Fixture::new().dotfile("testfile.sh", FILE_CONTENT);
         let mut sectioncount = 0;
 
         for section in testfile.sections {
             if let Section::Named(_, named_data) = section {
                 sectioncount += 1;
-                assert!(vec!["firstsection", "secondsection"].contains(&named_data.name.as_str()));
+                assert!(["firstsection", "secondsection"].contains(&named_data.name.as_str()));
             }
         }
 
         assert_eq!(sectioncount, 2);
     }
+
+    fn roundtrip(content: &str) -> String {
+        Fixture::new().dotfile("testfile.sh", content).to_string()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        assert_eq!(roundtrip(FILE_CONTENT), FILE_CONTENT);
+
+        let crlf = FILE_CONTENT.replace('\n', "\r\n");
+        assert_eq!(roundtrip(&crlf), crlf);
+
+        let bom = format!("\u{feff}{}\n\n", FILE_CONTENT);
+        assert_eq!(roundtrip(&bom), bom);
+
+        let spacing = "#...   test begin\n\n#...test hash abc\nhello\n\n#... test end\n\ntrailing";
+        assert_eq!(roundtrip(spacing), spacing);
+    }
+
+    #[test]
+    fn test_crlf_hash() {
+        // line endings are part of the content, so the unix hashes no longer match
+        let testfile = Fixture::new().dotfile("testfile.sh", FILE_CONTENT.replace('\n', "\r\n"));
+        assert!(testfile.modified);
+    }
+
+    #[test]
+    fn test_legacy_hash() {
+        let legacy = "#... test begin
+#... test hash 88968C2B101A58A04D3D8ABDBC2D46B7F1C55D0448108E752282EB4570AFCE98
+hello world
+testing123
+#... test end
+";
+        let mut testfile = Fixture::new().dotfile("testfile.sh", legacy);
+        assert!(!testfile.modified);
+        // compiling migrates the hash to the exact content hash
+        assert!(testfile.compile());
+        assert_eq!(
+            testfile.to_string(),
+            legacy.replace(
+                "88968C2B101A58A04D3D8ABDBC2D46B7F1C55D0448108E752282EB4570AFCE98",
+                "0DD9C99DCB5D37FB872A7FC801D8EE38922E477AE4C65F6486B02AE31981C28E"
+            )
+        );
+    }
+
+    #[test]
+    fn test_block_comment() {
+        let syntax = CommentSyntax::block("<!--", "-->");
+        let comment = Specialcomment::from_line("<!--... fonts hash ABC -->", &syntax, 3)
+            .unwrap()
+            .remove(0);
+        assert_eq!(comment.comment_type, CommentType::HashInfo);
+        assert_eq!(comment.argument.unwrap().as_str(), "ABC");
+        assert!(Specialcomment::from_line("<!--... fonts begin", &syntax, 1)
+            .unwrap()
+            .is_empty());
+
+        let mut testsection = Section::new(1, 10, "test".to_string(), None, "".to_string());
+        testsection.push_str("body {}\n");
+        testsection.finalize();
+        testsection.compile();
+        assert!(testsection
+            .output(&CommentSyntax::from("/* */"), MarkerStyle::Expanded)
+            .starts_with("/*... test begin */\n/*... test hash "));
+    }
+
+    #[test]
+    fn test_language_registry() {
+        let mut registry = LanguageRegistry::builtin();
+        let lua = registry.detect("/home/user/.config/awesome/rc.lua", &[]);
+        assert_eq!(lua.syntax, CommentSyntax::line("--"));
+        assert_eq!(lua.rule, SyntaxRule::Extension("lua".to_string()));
+
+        let env = registry.detect("/usr/bin/tool", &["#!/usr/bin/env -S python3 -u"]);
+        assert_eq!(env.rule, SyntaxRule::Interpreter("python3".to_string()));
+
+        registry
+            .add_toml(
+                "[nvimconf]\ncomment = \"--\"\nglobs = [\"nvim/*.conf\"]
+[myconf]\ncomment = \";\"\nextensions = [\"conf\"]\n",
+                std::path::Path::new("languages.toml"),
+            )
+            .unwrap();
+        let glob = registry.detect("/home/user/.config/nvim/init.conf", &[]);
+        assert_eq!(glob.language, "nvimconf");
+        assert_eq!(glob.rule, SyntaxRule::Glob("nvim/*.conf".to_string()));
+        // user extensions win over built-in file names
+        let conf = registry.detect("/etc/fonts/fonts.conf", &[]);
+        assert_eq!(conf.language, "myconf");
+        assert_eq!(conf.rule, SyntaxRule::Extension("conf".to_string()));
+    }
+
+    #[test]
+    fn test_syntax_declaration() {
+        let registry = LanguageRegistry::builtin();
+        let declared = registry.detect("/tmp/weird", &["#!/bin/sh", "//... all syntax //"]);
+        assert_eq!(declared.syntax, CommentSyntax::line("//"));
+        assert_eq!(declared.rule, SyntaxRule::Declaration(2));
+
+        // declarations below the first line of content are content
+        let content = registry.detect(
+            "/tmp/weird.sh",
+            &["#!/bin/sh", "cat <<EOF", "//... all syntax //"],
+        );
+        assert_eq!(content.syntax, CommentSyntax::line("#"));
+        assert_eq!(content.rule, SyntaxRule::Extension("sh".to_string()));
+
+        let block = registry.detect("/tmp/weird.sh", &["/*... all syntax /* */ */"]);
+        assert_eq!(block.syntax, CommentSyntax::block("/*", "*/"));
+
+        let vim = registry.detect("/tmp/weird", &["-- config", "-- vim: set ft=lua ts=4:"]);
+        assert_eq!(vim.syntax, CommentSyntax::line("--"));
+        assert_eq!(vim.rule, SyntaxRule::Modeline("lua".to_string()));
+
+        let emacs = registry.detect("/tmp/weird", &[";; -*- mode: emacs-lisp -*-"]);
+        assert_eq!(emacs.syntax, CommentSyntax::line(";"));
+    }
+
+    #[test]
+    fn test_combined_comment() {
+        let comments = Specialcomment::from_line(
+            "#... name begin hash=ABC source=../x.conf",
+            &CommentSyntax::line("#"),
+            4,
+        )
+        .unwrap();
+        assert_eq!(comments.len(), 3);
+        assert_eq!(comments[1].comment_type, CommentType::HashInfo);
+        assert_eq!(comments[2].argument.as_deref(), Some("../x.conf"));
+    }
+
+    #[test]
+    fn test_marker_style() {
+        let compact = "#!/bin/sh
+#... test begin hash=0DD9C99DCB5D37FB872A7FC801D8EE38922E477AE4C65F6486B02AE31981C28E
+hello world
+testing123
+#... test end
+";
+        let expanded = "#!/bin/sh
+#... test begin
+#... test hash 0DD9C99DCB5D37FB872A7FC801D8EE38922E477AE4C65F6486B02AE31981C28E
+hello world
+testing123
+#... test end
+";
+        let fixture = Fixture::new();
+        let mut testfile = fixture.dotfile("compact.sh", compact);
+        assert_eq!(testfile.markerstyle, MarkerStyle::Compact);
+        assert!(!testfile.modified);
+        assert!(!testfile.set_marker_style(MarkerStyle::Compact));
+        assert!(testfile.set_marker_style(MarkerStyle::Expanded));
+        assert_eq!(testfile.to_string(), expanded);
+
+        let mut testfile = fixture.dotfile("expanded.sh", expanded);
+        assert_eq!(testfile.markerstyle, MarkerStyle::Expanded);
+        assert!(testfile.set_marker_style(MarkerStyle::Compact));
+        assert_eq!(testfile.to_string(), compact);
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens = tokenize(
+            r#"all  target "~/.config/Some App/settings.conf" sha256=ab 'a\b' "x=y" c\ d"#,
+        )
+        .unwrap();
+        let values = tokens.iter().map(|t| t.value.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![
+                "all",
+                "target",
+                "~/.config/Some App/settings.conf",
+                "ab",
+                "a\\b",
+                "x=y",
+                "c d"
+            ]
+        );
+        assert_eq!(tokens[3].key.as_deref(), Some("sha256"));
+        assert_eq!(tokens[5].key, None);
+        assert!(tokenize("all target \"unterminated").is_err());
+
+        for argument in [
+            "~/.config/Some App/settings.conf",
+            "a\"b\\c",
+            "hash=x",
+            "",
+            "plain",
+        ] {
+            let tokens = tokenize(&quote(argument)).unwrap();
+            assert_eq!(tokens.len(), 1);
+            assert_eq!(tokens[0].key, None);
+            assert_eq!(tokens[0].value, argument);
+        }
+
+        let syntax = CommentSyntax::line("#");
+        let line = Specialcomment::new_string(
+            &syntax,
+            CommentType::SourceInfo,
+            "name",
+            Some("../Some Dir/x.conf"),
+            &[("sha256".to_string(), "AB".to_string())],
+        );
+        assert_eq!(line, "#... name source \"../Some Dir/x.conf\" sha256=AB");
+        let comment = Specialcomment::from_line(&line, &syntax, 1)
+            .unwrap()
+            .remove(0);
+        assert_eq!(comment.argument.as_deref(), Some("../Some Dir/x.conf"));
+        assert_eq!(
+            comment.options,
+            vec![("sha256".to_string(), "AB".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let content = "#... first begin
+#... first hash ABC
+#... second begin
+#... second hash ABC
+#... first end
+#... second end
+#... third bgin
+#... fourth begin
+#... fourth hash ABC
+#... fourth hash DEF
+#... fourth end
+";
+        let testfile = Fixture::new().dotfile("testfile.sh", content);
+        let codes = testfile
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            vec![
+                ("overlapping-sections", 3),
+                ("unknown-keyword", 7),
+                ("duplicate-marker", 10)
+            ]
+        );
+        let overlap = &testfile.diagnostics[0];
+        assert!(overlap.message.contains("second overlaps section first"));
+        assert_eq!(overlap.related[0].text.as_deref(), Some("#... first begin"));
+        assert_eq!(testfile.diagnostics[1].column, 12);
+        // the broken sections are left alone as content
+        assert_eq!(testfile.to_string(), content);
+    }
+
+    #[test]
+    fn test_lint() {
+        let fixture = Fixture::new();
+        fixture.write(
+            "testfile.sh",
+            "#... all permissions 0644
+#... all permissions 999
+#... all target ~/test
+#... section begin
+#... section target ~/other
+#... section end
+",
+        );
+        fixture.write("binary", [0, 0xff, 0xfe]);
+
+        let report = LintReport::new(&fixture.dir.path().to_path_buf());
+        assert_eq!(report.files, 1);
+        let quickfix = report.output(LintFormat::Quickfix);
+        let codes = quickfix
+            .lines()
+            .map(|line| line.rsplit_once('[').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            vec!["invalid-permissions]", "missing-hash]", "file-only]"]
+        );
+        assert!(quickfix.contains("testfile.sh:2:"));
+        assert!(report
+            .output(LintFormat::Json)
+            .starts_with("{\"files\":1,\"errors\":3,\"warnings\":0,"));
+    }
+
+    #[test]
+    fn test_format() {
+        let content = "#!/bin/sh
+echo hi
+#  ...   all   permissions 644
+#...firstsection start
+#... firstsection source \"a b\"
+#... firstsection hash ABC
+echo
+#... firstsection stop
+#... all target ~/test
+";
+        let fixture = Fixture::new();
+        // the source has to exist to not cause a warning
+        fixture.write("a b", "");
+        let testpath = fixture.write("testfile.sh", content);
+        let mut dotfile = DotFile::from_pathbuf(&testpath).unwrap();
+        assert!(dotfile.format());
+        assert_eq!(
+            dotfile.to_string(),
+            "#!/bin/sh
+#... all target ~/test
+#... all permissions 644
+echo hi
+#... firstsection begin
+#... firstsection hash ABC
+#... firstsection source \"a b\"
+echo
+#... firstsection end
+"
+        );
+        dotfile.write_to_file(&mut fixture.session()).unwrap();
+
+        let mut formatted = DotFile::from_pathbuf(&testpath).unwrap();
+        assert!(formatted.diagnostics.is_empty());
+        assert_eq!(formatted.targetfile.as_deref(), Some("~/test"));
+        assert!(formatted.modified);
+        assert!(!formatted.format());
+    }
+
+    #[test]
+    fn test_indented_section() {
+        let fixture = Fixture::new();
+        let sourcepath = fixture.write(
+            "source.yml",
+            "#... items begin\n#... items hash 0\n- a\n\n- b\n#... items end\n",
+        );
+        let targetpath = fixture.write(
+            "target.yml",
+            "list:
+\t#... items begin
+\t#... items hash 0
+\t- old
+\t#... items end
+other: 1
+",
+        );
+
+        for path in [&sourcepath, &targetpath] {
+            let mut dotfile = DotFile::from_pathbuf(path).unwrap();
+            assert!(dotfile.compile());
+            dotfile.write_to_file(&mut fixture.session()).unwrap();
+        }
+        let source = DotFile::from_pathbuf(&sourcepath).unwrap();
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        // content is hashed relative to the marker indentation
+        let Section::Named(data, named_data) = &target.sections[1] else {
+            panic!("section not found");
+        };
+        assert_eq!(data.indent, "\t");
+        assert_eq!(data.content, "- old\n");
+        assert_eq!(named_data.hash, sha256::digest("- old\n").to_uppercase());
+
+        assert!(target.applyfile(&source, ConflictStyle::Report, &fixture.session()));
+        let output = target.to_string();
+        assert!(output.starts_with("list:\n\t#... items begin\n\t#... items hash "));
+        assert!(output.ends_with("\t- a\n\n\t- b\n\t#... items end\nother: 1\n"));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        let target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(!target.modified);
+        assert_eq!(target.to_string(), output);
+    }
+
+    #[test]
+    fn test_indentation_kept() {
+        // lines without the indentation and whitespace only lines stay as they are
+        let body = "    - a\n  partial\n    \n  \n\n";
+        let mut target = Fixture::new().dotfile(
+            "target.yml",
+            format!(
+                "list:\n    #... items begin\n    #... items hash 0\n{}    #... items end\n",
+                body
+            ),
+        );
+        assert!(target.compile());
+        assert!(target
+            .to_string()
+            .ends_with(&format!("\n{}    #... items end\n", body)));
+    }
+
+    #[test]
+    fn test_nested_sections() {
+        let content = "#... keys begin
+#... keys hash 0
+bind a
+  #... custom begin
+  #... custom hash 0
+  bind user
+  #... custom end
+bind z
+#... keys end
+";
+        let fixture = Fixture::new();
+        let sourcepath = fixture.write("source.conf", content);
+        let targetpath = fixture.write("target.conf", content);
+        for path in [&sourcepath, &targetpath] {
+            let mut dotfile = DotFile::from_pathbuf(path).unwrap();
+            assert!(dotfile.diagnostics.is_empty());
+            assert!(dotfile.compile());
+            dotfile.write_to_file(&mut fixture.session()).unwrap();
+        }
+
+        let target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert_eq!(target.count_named_sections(), 2);
+        // the parent hash only covers the name of the nested section
+        let Some(Section::Named(data, _)) = target.get_section("keys") else {
+            panic!("section not found");
+        };
+        assert_eq!(data.content, "bind a\n  ... custom\nbind z\n");
+
+        // the user changes the nested section, upstream changes the parent
+        let targetcontent = target.to_string().replace("bind user", "bind mine");
+        fixture.write("target.conf", targetcontent);
+        let sourcecontent = DotFile::from_pathbuf(&sourcepath)
+            .unwrap()
+            .to_string()
+            .replace("bind a", "bind b");
+        fixture.write("source.conf", sourcecontent);
+        let mut source = DotFile::from_pathbuf(&sourcepath).unwrap();
+        source.compile();
+
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.modified);
+        assert!(target.applyfile(&source, ConflictStyle::Report, &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+
+        let target = DotFile::from_pathbuf(&targetpath).unwrap();
+        let output = target.to_string();
+        assert!(output.contains("bind b\n"));
+        assert!(output.contains("  bind mine\n"));
+        let modified = target
+            .sections
+            .iter()
+            .flat_map(|section| section.named_sections())
+            .map(|(_, named_data)| (named_data.name.as_str(), named_data.is_modified()))
+            .collect::<Vec<_>>();
+        assert_eq!(modified, vec![("keys", false), ("custom", true)]);
+
+        // upstream dropped the nested section the user changed
+        fixture.write(
+            "source.conf",
+            "#... keys begin\n#... keys hash 0\nbind c\n#... keys end\n",
+        );
+        let mut source = DotFile::from_pathbuf(&sourcepath).unwrap();
+        source.compile();
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(!target.applyfile(&source, ConflictStyle::Report, &fixture.session()));
+        assert_eq!(target.to_string(), output);
+    }
+
+    #[test]
+    fn test_update() {
+        let fixture = Fixture::new();
+        let sourcepath = fixture.write(
+            "source.sh",
+            "#... first begin\n#... first hash 0\nnew first\n#... first end
+#... second begin\n#... second hash 0\nnew second\n#... second end\n",
+        );
+        let source = sourcepath.to_str().unwrap();
+        let mut target = fixture.dotfile(
+            "target.sh",
+            format!(
+                "#... first begin\n#... first source {0}\n#... first hash 0\nold first\n#... first end
+#... second begin\n#... second source {0}\n#... second hash 0\nold second\n#... second end\n",
+                source
+            ),
+        );
+        target.compile();
+
+        // the user changed the second section
+        let content = target.to_string().replace("old second", "my second");
+        let targetpath = fixture.write("target.sh", content);
+
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        let output = target.to_string();
+        assert!(output.contains(&format!("#... first source {}\n", source)));
+        assert!(output.contains("new first\n"));
+        assert!(output.contains("my second\n"));
+        assert!(!target.get_section("first").unwrap().is_modified());
+        assert!(!target.update(&["first"], &UpdateOptions::default(), &fixture.session()));
+    }
+
+    #[test]
+    fn test_update_metafile() {
+        // files managed by a metafile are replaced as a whole
+        let fixture = Fixture::new();
+        let metasource = fixture.write("source.json", "{\"new\": true}\n");
+        let metatarget = fixture.write("target.json", "{}\n");
+        MetaFile::read(metatarget.clone())
+            .write_to_file(&mut fixture.session())
+            .unwrap();
+        let metapath = fixture.path("target.json.imosid.toml");
+        let metacontent = std::fs::read_to_string(&metapath).unwrap();
+        fixture.write(
+            "target.json.imosid.toml",
+            format!(
+                "{}source = {:?}\n",
+                metacontent,
+                metasource.to_str().unwrap()
+            ),
+        );
+        let mut target = DotFile::from_pathbuf(&metatarget).unwrap();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        let target = DotFile::from_pathbuf(&metatarget).unwrap();
+        assert_eq!(target.to_string(), "{\"new\": true}\n");
+        assert!(!target.modified);
+    }
+
+    #[test]
+    fn test_source_uri() {
+        let base = Path::new("/home/user/dotfiles/zsh");
+        let uri = SourceUri::parse("../common/shell.sh#aliases", base).unwrap();
+        assert_eq!(
+            uri.location,
+            SourceLocation::File(PathBuf::from("/home/user/dotfiles/zsh/../common/shell.sh"))
+        );
+        assert_eq!(uri.section_name("other"), "aliases");
+        let uri = SourceUri::parse("file:///usr/share/my%20files/zshrc#prompt", base).unwrap();
+        assert_eq!(
+            uri.location,
+            SourceLocation::File(PathBuf::from("/usr/share/my files/zshrc"))
+        );
+        assert_eq!(uri.section.as_deref(), Some("prompt"));
+        assert_eq!(SourceUri::parse("shell.sh", base).unwrap().section, None);
+    }
+
+    #[test]
+    fn test_invalid_source_uri() {
+        let base = Path::new("/home/user/dotfiles/zsh");
+        assert!(SourceUri::parse("file://relative/path", base).is_err());
+        assert!(SourceUri::parse("ftp://host/file", base).is_err());
+        assert!(SourceUri::parse("shell.sh#", base).is_err());
+    }
+
+    #[test]
+    fn test_source_diagnostics() {
+        let target = Fixture::new().dotfile(
+            "zshrc",
+            "#... other begin
+#... other source ftp://host/file
+#... other hash 0
+#... other end
+#... missing begin
+#... missing source missing.sh
+#... missing hash 0
+#... missing end
+",
+        );
+        let codes = target
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.line))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec![("invalid-source", 2), ("unresolved-source", 6)]);
+    }
+
+    #[test]
+    fn test_relative_source() {
+        // relative sources resolve against the declaring file
+        let fixture = Fixture::new();
+        fixture.write(
+            "common/shell.sh",
+            "#... aliases begin\n#... aliases hash 0\nalias ls=exa\n#... aliases end\n",
+        );
+        let mut target = fixture.dotfile(
+            "zsh/zshrc",
+            "#... myaliases begin
+#... myaliases source ../common/shell.sh#aliases
+#... myaliases hash 0
+#... myaliases end
+",
+        );
+        assert!(target.diagnostics.is_empty());
+        target.compile();
+        assert!(target.update(
+            &["myaliases"],
+            &UpdateOptions::default(),
+            &fixture.session()
+        ));
+        assert!(target.to_string().starts_with(
+            "#... myaliases begin
+#... myaliases hash "
+        ));
+        assert!(target
+            .to_string()
+            .contains("alias ls=exa\n#... myaliases end\n"));
+    }
+
+    #[test]
+    fn test_git_source_uri() {
+        let base = Path::new("/home/user");
+        let uri =
+            SourceUri::parse("git+file:///srv/dots.git?ref=v1.2#zsh/zshrc:prompt", base).unwrap();
+        assert_eq!(
+            uri.location,
+            SourceLocation::Git {
+                repository: PathBuf::from("/srv/dots.git"),
+                reference: String::from("v1.2"),
+                path: String::from("zsh/zshrc"),
+            }
+        );
+        assert_eq!(uri.section.as_deref(), Some("prompt"));
+        let uri = SourceUri::parse("git+file:///srv/dots.git#zshrc", base).unwrap();
+        assert!(
+            matches!(uri.location, SourceLocation::Git { ref reference, .. } if reference == "HEAD")
+        );
+        assert_eq!(uri.section, None);
+        assert!(SourceUri::parse("git+file:///srv/dots.git", base).is_err());
+        assert!(SourceUri::parse("git+https://host/dots.git#zshrc", base).is_err());
+        assert!(
+            SourceUri::parse("git+file:///srv/dots.git?ref=--upload-pack#zshrc", base).is_err()
+        );
+    }
+
+    // build a bare repository with a tag v1 and a newer commit on top,
+    // return its path and both commits
+    fn git_repository(fixture: &Fixture) -> (PathBuf, String, String) {
+        let worktree = fixture.path("work");
+        let bare = fixture.path("dots.git");
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&worktree)
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}", output);
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        let write_source = |text: &str| {
+            fixture.write(
+                "work/zsh/zshrc",
+                format!(
+                    "#... prompt begin\n#... prompt hash 0\n{}\n#... prompt end\n",
+                    text
+                ),
+            );
+        };
+        write_source("PS1='v1 '");
+        git(&["init", "--quiet"]);
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "-m", "v1"]);
+        git(&["tag", "v1"]);
+        let v1 = git(&["rev-parse", "HEAD"]);
+        write_source("PS1='v2 '");
+        git(&["commit", "--quiet", "-am", "v2"]);
+        let v2 = git(&["rev-parse", "HEAD"]);
+        git(&["clone", "--quiet", "--bare", ".", bare.to_str().unwrap()]);
+        (bare, v1, v2)
+    }
+
+    #[test]
+    fn test_git_source() {
+        let fixture = Fixture::new();
+        let (bare, v1, _) = git_repository(&fixture);
+        let source = format!("git+file://{}?ref=v1#zsh/zshrc", bare.to_str().unwrap());
+        let targetpath = fixture.write(
+            "zshrc",
+            format!(
+                "#... prompt begin\n#... prompt source {}\n#... prompt hash 0\n#... prompt end\n",
+                source
+            ),
+        );
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.diagnostics.is_empty());
+        target.compile();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+
+        // the resolved commit is pinned in the source comment
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        let output = target.to_string();
+        assert!(output.contains("PS1='v1 '\n"));
+        assert!(output.contains(&format!("#... prompt source {} commit={}\n", source, v1)));
+        let Some(Section::Named(_, named_data)) = target.get_section("prompt") else {
+            panic!("section prompt missing");
+        };
+        assert_eq!(named_data.source_commit(), Some(v1.as_str()));
+        assert!(!target.update(&[], &UpdateOptions::default(), &fixture.session()));
+    }
+
+    #[test]
+    fn test_git_source_branch() {
+        // following a branch moves the pin along
+        let fixture = Fixture::new();
+        let (bare, v1, v2) = git_repository(&fixture);
+        let source = format!("git+file://{}#zsh/zshrc", bare.to_str().unwrap());
+        let mut target = fixture.dotfile(
+            "zshrc",
+            format!(
+                "#... prompt begin\n#... prompt source {} commit={}\n#... prompt hash 0\n#... prompt end\n",
+                source, v1
+            ),
+        );
+        target.compile();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        let output = target.to_string();
+        assert!(output.contains("PS1='v2 '\n"));
+        assert!(output.contains(&format!("#... prompt source {} commit={}\n", source, v2)));
+    }
+
+    #[test]
+    fn test_http_source() {
+        let fixture = Fixture::new();
+        let session = fixture.session();
+
+        // stand-in server that answers revalidations with 304
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for stream in listener.incoming().take(4) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(&stream);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                let body =
+                    "#... prompt begin\n#... prompt hash 0\nPS1='remote '\n#... prompt end\n";
+                let response = if !request.starts_with("GET /zshrc ") {
+                    String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                } else if request.contains("If-None-Match: \"v1\"") {
+                    String::from(
+                        "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let url = format!("http://127.0.0.1:{}/zshrc", port);
+        let mut target = fixture.dotfile(
+            "zshrc",
+            format!(
+                "#... prompt begin\n#... prompt source {}\n#... prompt hash 0\n#... prompt end\n",
+                url
+            ),
+        );
+        assert!(target.diagnostics.is_empty());
+        target.compile();
+        assert!(target.update(&[], &UpdateOptions::default(), &session));
+        assert!(target.to_string().contains("PS1='remote '\n"));
+        assert!(!target.update(&[], &UpdateOptions::default(), &session));
+
+        let base = fixture.path("");
+        let missing =
+            SourceUri::parse(&format!("http://127.0.0.1:{}/missing", port), &base).unwrap();
+        assert!(missing.read(false, false, &session).is_err());
+        assert!(missing.read(true, false, &session).is_err());
+        let uri = SourceUri::parse(&format!("{}#prompt", url), &base).unwrap();
+        assert_eq!(uri.location, SourceLocation::Http(url.clone()));
+        assert_eq!(uri.filename(), "zshrc");
+        assert!(uri
+            .read(true, false, &session)
+            .unwrap()
+            .text
+            .contains("PS1='remote '\n"));
+        assert!(uri
+            .read(false, false, &session)
+            .unwrap()
+            .text
+            .contains("PS1='remote '\n"));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1].contains("If-None-Match: \"v1\""));
+        assert!(requests[3].contains("If-None-Match: \"v1\""));
+    }
+
+    #[test]
+    fn test_command_source_uri() {
+        let base = Path::new("/home/user");
+        let uri = SourceUri::parse("cmd:dircolors -p 'a b' LC_ALL=C", base).unwrap();
+        assert_eq!(
+            uri.location,
+            SourceLocation::Command(vec![
+                String::from("dircolors"),
+                String::from("-p"),
+                String::from("a b"),
+                String::from("LC_ALL=C"),
+            ])
+        );
+        assert_eq!(uri.to_string(), "cmd:dircolors -p \"a b\" \"LC_ALL=C\"");
+        assert!(SourceUri::parse("cmd:", base).is_err());
+    }
+
+    // allows the commands used by the tests, in every directory
+    fn command_policy() -> CommandPolicy {
+        CommandPolicy::from_toml(
+            "allow = [\"printf\", \"sh\", \"sleep\"]\ntrusted = [\"/\"]\ntimeout = 5\n",
+        )
+        .unwrap()
+    }
+
+    const COMMAND_SOURCES: &str = "#... colors begin
+#... colors source \"cmd:printf 'one\\\\ntwo'\"
+#... colors hash 0
+#... colors end
+#... broken begin
+#... broken source \"cmd:sh -c \\\"echo oops >&2; exit 3\\\"\"
+#... broken hash 0
+old
+#... broken end
+#... slow begin
+#... slow source \"cmd:sleep 5\" timeout=1
+#... slow hash 0
+#... slow end
+#... forbidden begin
+#... forbidden source \"cmd:ls\"
+#... forbidden hash 0
+#... forbidden end
+";
+
+    #[test]
+    fn test_command_policy() {
+        let policy = command_policy();
+        assert!(policy.allows("printf"));
+        assert!(!policy.allows("/usr/bin/printf"));
+        assert!(policy.trusts(&Fixture::new().path("")));
+        assert!(!CommandPolicy::default().trusts(Path::new("/")));
+        assert!(CommandPolicy::from_toml("allow = \"sh\"").is_err());
+    }
+
+    #[test]
+    fn test_command_source() {
+        let fixture = Fixture::new();
+        let mut target = fixture.dotfile("dircolors", COMMAND_SOURCES);
+        assert!(target.diagnostics.is_empty());
+        target.compile();
+
+        let options = UpdateOptions {
+            commands: command_policy(),
+            ..Default::default()
+        };
+        assert!(target.update(&["colors"], &options, &fixture.session()));
+        assert!(!target.update(&["colors"], &options, &fixture.session()));
+        assert!(target.to_string().contains("one\ntwo\n#... colors end\n"));
+        assert!(!target.get_section("colors").unwrap().is_modified());
+    }
+
+    #[test]
+    fn test_command_source_dry_run() {
+        // dry runs do not run commands
+        let fixture = Fixture::new();
+        let mut target = fixture.dotfile("dircolors", COMMAND_SOURCES);
+        target.compile();
+        let dry_run = UpdateOptions {
+            commands: command_policy(),
+            dry_run: true,
+            ..Default::default()
+        };
+        assert!(!target.update(&["colors"], &dry_run, &fixture.session()));
+    }
+
+    #[test]
+    fn test_command_source_trust() {
+        // --trust runs commands of files outside the trusted directories
+        let fixture = Fixture::new();
+        let mut target = fixture.dotfile("dircolors", COMMAND_SOURCES);
+        target.compile();
+        let untrusted = UpdateOptions {
+            commands: CommandPolicy {
+                trusted: Vec::new(),
+                ..command_policy()
+            },
+            ..Default::default()
+        };
+        assert!(!target.update(&["colors"], &untrusted, &fixture.session()));
+        let trusted = UpdateOptions {
+            trust: true,
+            ..untrusted
+        };
+        assert!(target.update(&["colors"], &trusted, &fixture.session()));
+    }
+
+    #[test]
+    fn test_command_source_errors() {
+        let fixture = Fixture::new();
+        let mut target = fixture.dotfile("dircolors", COMMAND_SOURCES);
+        target.compile();
+        let options = UpdateOptions {
+            commands: command_policy(),
+            ..Default::default()
+        };
+        assert!(!target.update(
+            &["broken", "slow", "forbidden"],
+            &options,
+            &fixture.session()
+        ));
+        assert!(target.to_string().contains("old\n#... broken end\n"));
+        let codes = target
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            vec![
+                ("command-stderr", 5),
+                ("command-failed", 5),
+                ("command-failed", 10),
+                ("command-not-allowed", 14)
+            ]
+        );
+        assert!(target.diagnostics[0].message.ends_with("oops"));
+        assert!(target.diagnostics[2].message.contains("timed out"));
+    }
+
+    // source.sh with a section env, the target pins it to the reviewed content
+    fn pinned_source(fixture: &Fixture, reviewed: &str, upstream: &str) -> PathBuf {
+        fixture.write(
+            "source.sh",
+            format!(
+                "#... env begin\n#... env hash 0\n{}#... env end\n",
+                upstream
+            ),
+        );
+        let targetpath = fixture.write(
+            "target.sh",
+            format!(
+                "#... env begin\n#... env source source.sh sha256={}\n#... env hash 0\n#... env end\n",
+                content_digest(reviewed).to_uppercase()
+            ),
+        );
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        target.compile();
+        target.write_to_file(&mut fixture.session()).unwrap();
+        targetpath
+    }
+
+    #[test]
+    fn test_source_pin() {
+        let fixture = Fixture::new();
+        let targetpath = pinned_source(&fixture, "export A=1\n", "export A=1\n");
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.diagnostics.is_empty());
+        assert!(target.update(&["env"], &UpdateOptions::default(), &fixture.session()));
+        assert!(target.to_string().contains("export A=1\n"));
+    }
+
+    #[test]
+    fn test_invalid_pin() {
+        let fixture = Fixture::new();
+        // the source has to exist to not cause a warning
+        fixture.write("source.sh", "");
+        let target = fixture.dotfile(
+            "target.sh",
+            "#... bad begin\n#... bad source source.sh#env sha256=abc\n#... bad hash 0\n#... bad end\n",
+        );
+        let codes = target
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.line))
+            .collect::<Vec<_>>();
+        assert_eq!(codes, vec![("invalid-pin", 2)]);
+    }
+
+    #[test]
+    fn test_pin_mismatch() {
+        // upstream changed after it was reviewed
+        let fixture = Fixture::new();
+        let targetpath = pinned_source(&fixture, "export A=1\n", "export A=2\n");
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(!target.update(&["env"], &UpdateOptions::default(), &fixture.session()));
+        assert_eq!(target.diagnostics.last().unwrap().code, "pin-mismatch");
+        assert!(!target.to_string().contains("export A=2\n"));
+
+        let accept = UpdateOptions {
+            accept_new: true,
+            ..Default::default()
+        };
+        assert!(target.update(&["env"], &accept, &fixture.session()));
+        let output = target.to_string();
+        assert!(output.contains("export A=2\n"));
+        assert!(output.contains(&format!(
+            "#... env source source.sh sha256={}\n",
+            content_digest("export A=2\n")
+        )));
+    }
+
+    #[test]
+    fn test_metafile_pin() {
+        // metafiles keep their pin next to the source
+        let fixture = Fixture::new();
+        fixture.write("source.json", "{\"new\": true}\n");
+        let metatarget = fixture.write("target.json", "{}\n");
+        MetaFile::read(metatarget.clone())
+            .write_to_file(&mut fixture.session())
+            .unwrap();
+        let metacontent = std::fs::read_to_string(fixture.path("target.json.imosid.toml")).unwrap();
+        fixture.write(
+            "target.json.imosid.toml",
+            format!(
+                "{}source = \"source.json\"\nsha256 = \"{}\"\n",
+                metacontent,
+                content_digest("{}\n")
+            ),
+        );
+        let mut target = DotFile::from_pathbuf(&metatarget).unwrap();
+        assert!(!target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        let accept = UpdateOptions {
+            accept_new: true,
+            ..Default::default()
+        };
+        assert!(target.update(&[], &accept, &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        let target = DotFile::from_pathbuf(&metatarget).unwrap();
+        assert_eq!(target.to_string(), "{\"new\": true}\n");
+        assert_eq!(
+            target.metafile.unwrap().sourcepin,
+            Some(content_digest("{\"new\": true}\n"))
+        );
+    }
+
+    #[test]
+    fn test_nested_pin() {
+        // the pin of a section covers its nested sections as well
+        let fixture = Fixture::new();
+        let write_nested = |text: &str| {
+            fixture.write(
+                "nested.sh",
+                format!(
+                    "#... outer begin\n#... outer hash 0\na\n  #... inner begin\n  #... inner hash 0\n  {}  #... inner end\n#... outer end\n",
+                    text
+                ),
+            );
+        };
+        write_nested("b=1\n");
+        let mut target = fixture.dotfile(
+            "target.sh",
+            format!(
+                "#... outer begin\n#... outer source nested.sh sha256={}\n#... outer hash 0\n#... outer end\n",
+                content_digest("a\n  b=1\n")
+            ),
+        );
+        target.compile();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        write_nested("b=2\n");
+        let mut target = DotFile::from_pathbuf(&fixture.path("target.sh")).unwrap();
+        assert!(!target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        assert_eq!(target.diagnostics.last().unwrap().code, "pin-mismatch");
+    }
+
+    // write a compiled file with the given sections and their content
+    fn compiled_sections(fixture: &Fixture, name: &str, sections: &[(&str, &str)], source: bool) {
+        let mut content = String::new();
+        for (section, text) in sections {
+            content.push_str(&format!("#... {} begin\n", section));
+            if source {
+                content.push_str(&format!("#... {} source upstream.sh\n", section));
+            }
+            content.push_str(&format!(
+                "#... {0} hash 0\n{1}\n#... {0} end\n",
+                section, text
+            ));
+        }
+        let mut dotfile = fixture.dotfile(name, content);
+        dotfile.compile();
+        dotfile.write_to_file(&mut fixture.session()).unwrap();
+    }
+
+    #[test]
+    fn test_outdated() {
+        let fixture = Fixture::new();
+        let sections = [
+            ("same", "1"),
+            ("upstream", "1"),
+            ("local", "1"),
+            ("both", "1"),
+        ];
+        compiled_sections(&fixture, "target.sh", &sections, true);
+        compiled_sections(
+            &fixture,
+            "upstream.sh",
+            &[
+                ("same", "1"),
+                ("upstream", "2"),
+                ("local", "1"),
+                ("both", "2"),
+            ],
+            false,
+        );
+        let targetpath = fixture.path("target.sh");
+        let content = std::fs::read_to_string(&targetpath)
+            .unwrap()
+            .replace("1\n#... local end", "3\n#... local end")
+            .replace("1\n#... both end", "3\n#... both end");
+        fixture.write("target.sh", &content);
+
+        let report = OutdatedReport::new(&targetpath, true, &fixture.session());
+        let states = report
+            .sections
+            .iter()
+            .map(|status| (status.section.as_deref().unwrap(), status.freshness))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                ("same", Freshness::UpToDate),
+                ("upstream", Freshness::UpstreamNewer),
+                ("local", Freshness::Modified),
+                ("both", Freshness::Diverged),
+            ]
+        );
+        assert_eq!(report.exit_code(), 1);
+        // nothing is written
+        assert_eq!(std::fs::read_to_string(&targetpath).unwrap(), content);
+        assert!(report.output(OutdatedFormat::Json).starts_with(&format!(
+            "{{\"sections\":[{{\"file\":{:?},\"section\":\"same\",\"source\":\"upstream.sh\",\"status\":\"up-to-date\",\"message\":null}},",
+            targetpath.to_str().unwrap()
+        )));
+    }
+
+    #[test]
+    fn test_outdated_unknown() {
+        let fixture = Fixture::new();
+        let targetpath = fixture.write(
+            "target.sh",
+            "#... gone begin\n#... gone source missing.sh\n#... gone hash 0\n#... gone end\n",
+        );
+        let report = OutdatedReport::new(&targetpath, true, &fixture.session());
+        assert_eq!(report.sections[0].freshness, Freshness::Unknown);
+        assert_eq!(report.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_outdated_command() {
+        // commands are not run, which is no error
+        let fixture = Fixture::new();
+        let targetpath = fixture.write(
+            "target.sh",
+            "#... colors begin\n#... colors source \"cmd:dircolors -p\"\n#... colors hash 0\n#... colors end\n",
+        );
+        let report = OutdatedReport::new(&targetpath, true, &fixture.session());
+        assert_eq!(report.sections[0].freshness, Freshness::Skipped);
+        assert_eq!(report.exit_code(), 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let merged = merge("a\nb\nc\nd\n", "a\nB\nc\nd\n", "a\nb\nc\nD\ne\n");
+        assert_eq!(
+            (merged.content.as_str(), merged.conflicts),
+            ("a\nB\nc\nD\ne\n", 0)
+        );
+        let merged = merge("a\nb\nc\n", "a\nX\nc\n", "a\nY\nc\n");
+        assert_eq!(
+            merged.content,
+            "a\n<<<<<<< local\nX\n=======\nY\n>>>>>>> upstream\nc\n"
+        );
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(merge("a\n", "a\nb\n", "a\nb\n").content, "a\nb\n");
+    }
+
+    #[test]
+    fn test_merge_large() {
+        // large files with changes all over them
+        let base = (0..20000).map(|i| format!("{}\n", i)).collect::<String>();
+        let change = |text: &str, every: usize, to: &str| {
+            text.lines()
+                .enumerate()
+                .map(|(i, line)| match i % every {
+                    0 => format!("{}\n", to),
+                    _ => format!("{}\n", line),
+                })
+                .collect::<String>()
+        };
+        let local = change(&base, 1000, "local");
+        let upstream = change(&base, 777, "upstream");
+        let merged = merge(&base, &local, &upstream);
+        assert_eq!(merged.conflicts, 1);
+        assert!(merged.content.contains("\n999\nlocal\n1001\n"));
+        assert!(merged.content.contains("\n776\nupstream\n778\n"));
+    }
+
+    // compiled target taking the cfg section from source.conf, after a first update
+    fn merge_target(fixture: &Fixture) -> PathBuf {
+        merge_source(fixture, "one\ntwo\nthree\n");
+        let targetpath = fixture.write(
+            "target.conf",
+            "#... cfg begin\n#... cfg source source.conf\n#... cfg hash 0\n#... cfg end\n",
+        );
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        target.compile();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+
+        // the user changes the first line
+        let content = std::fs::read_to_string(&targetpath)
+            .unwrap()
+            .replace("one\n", "mine\n");
+        fixture.write("target.conf", content);
+        targetpath
+    }
+
+    fn merge_source(fixture: &Fixture, text: &str) {
+        fixture.write(
+            "source.conf",
+            format!("#... cfg begin\n#... cfg hash 0\n{}#... cfg end\n", text),
+        );
+    }
+
+    #[test]
+    fn test_merge_update() {
+        let fixture = Fixture::new();
+        let targetpath = merge_target(&fixture);
+        merge_source(&fixture, "one\ntwo\nupstream\n");
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        let target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.to_string().contains("mine\ntwo\nupstream\n"));
+        // the changes of the user are still changes on top of upstream
+        assert!(target.get_section("cfg").unwrap().is_modified());
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let fixture = Fixture::new();
+        let targetpath = merge_target(&fixture);
+        // both change the same line
+        merge_source(&fixture, "theirs\ntwo\nthree\n");
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(!target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        let markers = UpdateOptions {
+            conflicts: ConflictStyle::Markers,
+            ..Default::default()
+        };
+        assert!(target.update(&[], &markers, &fixture.session()));
+        assert!(target
+            .to_string()
+            .contains("<<<<<<< local\nmine\n=======\ntheirs\n>>>>>>> upstream\ntwo\nthree\n"));
+    }
+
+    #[test]
+    fn test_merge_metafile() {
+        // files managed by a metafile are merged as a whole
+        let fixture = Fixture::new();
+        fixture.write("source.json", "{\n\"a\": 1,\n\"m\": 0,\n\"b\": 1\n}\n");
+        let metatarget = fixture.write("target.json", "{\n\"a\": 1,\n\"m\": 0,\n\"b\": 1\n}\n");
+        MetaFile::read(metatarget.clone())
+            .write_to_file(&mut fixture.session())
+            .unwrap();
+        let metapath = fixture.path("target.json.imosid.toml");
+        let metacontent = std::fs::read_to_string(&metapath).unwrap();
+        fixture.write(
+            "target.json.imosid.toml",
+            format!("{}source = \"source.json\"\n", metacontent),
+        );
+        let mut target = DotFile::from_pathbuf(&metatarget).unwrap();
+        target.write_to_file(&mut fixture.session()).unwrap();
+        fixture.write("target.json", "{\n\"a\": 2,\n\"m\": 0,\n\"b\": 1\n}\n");
+        fixture.write("source.json", "{\n\"a\": 1,\n\"m\": 0,\n\"b\": 3\n}\n");
+        let mut target = DotFile::from_pathbuf(&metatarget).unwrap();
+        assert!(target.modified);
+        assert!(target.update(&[], &UpdateOptions::default(), &fixture.session()));
+        target.write_to_file(&mut fixture.session()).unwrap();
+        let target = DotFile::from_pathbuf(&metatarget).unwrap();
+        assert_eq!(target.to_string(), "{\n\"a\": 2,\n\"m\": 0,\n\"b\": 3\n}\n");
+        assert!(target.modified);
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\n", "a\n", "old", "new"), "");
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve";
+        assert_eq!(
+            unified_diff(old, new, "old", "new"),
+            "--- old\n+++ new\n@@ -1,3 +1,4 @@\n+0\n 1\n 2\n 3\n\
+             @@ -9,4 +10,4 @@\n 9\n 10\n 11\n-12\n+twelve\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_object_store() {
+        let fixture = Fixture::new();
+        let store = fixture.session().store;
+        let hash = store.put("one\ntwo\n").unwrap();
+        assert_eq!(store.put("one\ntwo\n").unwrap(), hash);
+        assert_eq!(store.get(&hash.to_lowercase()).unwrap(), "one\ntwo\n");
+        assert_eq!(store.get("../objects"), None);
+        assert_eq!(store.hashes().unwrap(), vec![hash.clone()]);
+
+        let dotfile = DotFile::from_content(
+            "test.sh",
+            &format!("#... a begin\n#... a hash {}\none\nTWO\n#... a end\n", hash),
+        );
+        assert_eq!(dotfile.stored_hashes(), vec![hash.clone()]);
+        assert_eq!(
+            dotfile.original_diff(&store),
+            "--- test.sh section a (original)\n+++ test.sh section a\n\
+             @@ -1,2 +1,2 @@\n one\n-two\n+TWO\n"
+        );
+
+        store.remove(&hash).unwrap();
+        assert!(store.hashes().unwrap().is_empty());
+        assert_eq!(dotfile.original_diff(&store), "");
+    }
+
+    #[test]
+    fn test_damaged_object() {
+        // damaged objects are not used and replaced by the next put
+        let fixture = Fixture::new();
+        let store = fixture.session().store;
+        let hash = store.put("one\ntwo\n").unwrap().to_lowercase();
+        fixture.write(
+            &format!("data/objects/{}/{}", &hash[..2], &hash[2..]),
+            "one\n",
+        );
+        assert_eq!(store.get(&hash), None);
+        store.put("one\ntwo\n").unwrap();
+        assert_eq!(store.get(&hash).unwrap(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn test_managed_files() {
+        // gc keeps the contents of every file written with them
+        let fixture = Fixture::new();
+        let mut session = fixture.session();
+        assert!(session.store.managed().unwrap().is_empty());
+        let mut dotfile =
+            fixture.dotfile("test.sh", "#... a begin\n#... a hash 0\none\n#... a end\n");
+        dotfile.compile();
+        dotfile.write_to_file(&mut session).unwrap();
+        dotfile.write_to_file(&mut session).unwrap();
+        assert_eq!(
+            session.store.managed().unwrap(),
+            vec![fixture.path("test.sh")]
+        );
+    }
+
+    #[test]
+    fn test_labeled_diff() {
+        let labels = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| Some(name.to_string()))
+                .collect::<Vec<_>>()
+        };
+        // changes of different sections get hunks of their own
+        assert_eq!(
+            labeled_diff(
+                "a\n-\nb\n",
+                "A\n-\nB\n",
+                "old",
+                "new",
+                &labels(&["one", "two", "two"]),
+                &labels(&["one", "two", "two"])
+            ),
+            "--- old\n+++ new\n@@ -1,2 +1,2 @@ one\n-a\n+A\n -\n@@ -3,1 +3,1 @@ two\n-b\n+B\n"
+        );
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let fixture = Fixture::new();
+        let content = "top\n#... a begin\n#... a hash 0\none\n#... a end\n";
+        let mut dotfile = fixture.dotfile("test.sh", content);
+        assert_eq!(dotfile.pending_diff(), "");
+        assert!(dotfile.compile());
+        let diff = dotfile.pending_diff();
+        assert!(
+            diff.contains("@@ -1,5 +1,5 @@ a\n top\n #... a begin\n-#... a hash 0\n+#... a hash ")
+        );
+        assert!(dotfile.deletesection("a"));
+        assert!(dotfile.pending_diff().ends_with(
+            "@@ -1,5 +1,1 @@ a\n top\n-#... a begin\n-#... a hash 0\n-one\n-#... a end\n"
+        ));
+        assert_eq!(
+            std::fs::read_to_string(fixture.path("test.sh")).unwrap(),
+            content
+        );
+    }
+
+    #[test]
+    fn test_dry_run_apply() {
+        // apply shows what would be written to the target
+        let fixture = Fixture::new();
+        let mut session = fixture.session();
+        let targetpath = fixture.path("target.sh");
+        let mut source = fixture.dotfile(
+            "source.sh",
+            format!(
+                "#... all target {}\n#... a begin\n#... a hash 0\none\n#... a end\n",
+                targetpath.display()
+            ),
+        );
+        source.compile();
+        assert!(matches!(
+            source.apply(ConflictStyle::Report, true, &mut session),
+            ApplyResult::Changed
+        ));
+        assert!(!targetpath.exists());
+        assert!(matches!(
+            source.apply(ConflictStyle::Report, false, &mut session),
+            ApplyResult::Changed
+        ));
+        assert!(matches!(
+            source.apply(ConflictStyle::Report, true, &mut session),
+            ApplyResult::Unchanged
+        ));
+    }
+
+    #[test]
+    fn test_atomic_write() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let fixture = Fixture::new();
+        let realpath = fixture.write("real.sh", "old\n");
+        let linkpath = fixture.path("link.sh");
+        std::fs::set_permissions(&realpath, std::fs::Permissions::from_mode(0o751)).unwrap();
+        symlink("real.sh", &linkpath).unwrap();
+        // not every file system has extended attributes
+        let xattrs = xattr::set(&realpath, "user.imosid", b"kept").is_ok();
+
+        write_atomic(&linkpath, b"new\n", None).unwrap();
+        assert!(std::fs::symlink_metadata(&linkpath)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(std::fs::read_to_string(&realpath).unwrap(), "new\n");
+        let metadata = std::fs::metadata(&realpath).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o751);
+        if xattrs {
+            assert_eq!(
+                xattr::get(&realpath, "user.imosid").unwrap(),
+                Some(b"kept".to_vec())
+            );
+        }
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(fixture.path("")).unwrap().count(), 2);
+
+        write_atomic(&realpath, b"new\n", Some(0o600)).unwrap();
+        let metadata = std::fs::metadata(&realpath).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+    }
+
+    #[test]
+    fn test_atomic_write_failure() {
+        // a failed write leaves the file alone
+        let missing = Fixture::new().path("missing/file.sh");
+        assert!(write_atomic(&missing, b"new\n", None).is_err());
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_write_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // dotfiles keep their permissions comment
+        let fixture = Fixture::new();
+        let mut dotfile = fixture.dotfile(
+            "dot.sh",
+            "#... all permissions 640\n#... a begin\n#... a hash 0\nx\n#... a end\n",
+        );
+        dotfile.compile();
+        dotfile.write_to_file(&mut fixture.session()).unwrap();
+        let metadata = std::fs::metadata(fixture.path("dot.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o640);
+    }
+
+    // a backup set before and one after the file changed
+    fn backup_sets(fixture: &Fixture) -> (Backups, PathBuf, PathBuf) {
+        let backups = fixture.session().backups;
+        let path = fixture.write("test.sh", "first\n");
+        let created = fixture.path("created.sh");
+        let mut set = backups.create("imosid apply").unwrap();
+        set.add(&path).unwrap();
+        set.add(&created).unwrap();
+        std::fs::write(&path, "second\n").unwrap();
+        std::fs::write(&created, "new\n").unwrap();
+        let mut newer = backups.create("imosid update").unwrap();
+        newer.add(&path).unwrap();
+        newer.add(&path).unwrap();
+        std::fs::write(&path, "third\n").unwrap();
+        (backups, path, created)
+    }
+
+    #[test]
+    fn test_backups() {
+        let fixture = Fixture::new();
+        let (backups, _, _) = backup_sets(&fixture);
+        let sets = backups.list().unwrap();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].command, "imosid apply");
+        assert_eq!(sets[0].files.len(), 2);
+        assert!(sets[0].files[0].existed());
+        assert!(!sets[0].files[1].existed());
+        assert_eq!(sets[1].files.len(), 1);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let fixture = Fixture::new();
+        let (backups, path, created) = backup_sets(&fixture);
+        let first = backups.list().unwrap()[0].id.clone();
+        let journal = fixture.session().journal;
+
+        // without an id only the newest set is undone
+        assert_eq!(
+            backups.rollback(None, &[], true, &journal).unwrap(),
+            vec![path.clone()]
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third\n");
+        backups.rollback(None, &[], false, &journal).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+        // the rollback is a backup of its own
+        assert_eq!(backups.list().unwrap().len(), 3);
+
+        backups
+            .rollback(
+                Some(&first),
+                std::slice::from_ref(&created),
+                false,
+                &journal,
+            )
+            .unwrap();
+        assert!(!created.exists());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+        backups
+            .rollback(Some(&first), &[], false, &journal)
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\n");
+        assert!(backups
+            .rollback(Some("nope"), &[], false, &journal)
+            .is_err());
+        // every restored file is journaled
+        assert_eq!(journal.entries().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_backup_retention() {
+        let fixture = Fixture::new();
+        let (backups, _, _) = backup_sets(&fixture);
+        backups.create("imosid fmt").unwrap();
+
+        let policy = RetentionPolicy::from_toml("keep = 2").unwrap();
+        assert_eq!(backups.prune(&policy).unwrap().len(), 1);
+        assert_eq!(backups.list().unwrap().len(), 2);
+        assert!(RetentionPolicy::from_toml("keep = 0").is_err());
+        let policy = RetentionPolicy::from_toml("max_age = 1").unwrap();
+        assert!(backups.prune(&policy).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_backup() {
+        let fixture = Fixture::new();
+        let mut dotfile = fixture.dotfile("test.sh", "#!/bin/bash\necho old\n");
+        let mut session = fixture.session();
+        dotfile.write_to_file(&mut session).unwrap();
+        dotfile.write_to_file(&mut session).unwrap();
+
+        // one set for the run, with the file as it was before the first write
+        let sets = session.backups.list().unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].files.len(), 1);
+        assert!(fixture.path("state/backups").is_dir());
+        assert_eq!(
+            session
+                .backups
+                .rollback(None, &[], true, &session.journal)
+                .unwrap(),
+            vec![std::path::absolute(fixture.path("test.sh")).unwrap()]
+        );
+    }
+
+    // a compiled source with one section, applying to a target in the fixture
+    fn transaction_source(fixture: &Fixture, name: &str, target: &str, text: &str) -> PathBuf {
+        let path = fixture.write(
+            name,
+            format!(
+                "#... all target {}\n#... s begin\n#... s hash 0\n{}\n#... s end\n",
+                fixture.path(target).display(),
+                text
+            ),
+        );
+        let mut source = DotFile::from_pathbuf(&path).unwrap();
+        source.compile();
+        source.write_to_file(&mut fixture.session()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_transaction() {
+        let fixture = Fixture::new();
+        let sources = [
+            transaction_source(&fixture, "first.sh", "a/target.sh", "old"),
+            transaction_source(&fixture, "second.sh", "b/target.sh", "old"),
+        ];
+        let mut session = fixture.session();
+        let mut transaction = ApplyTransaction::plan(&sources, ConflictStyle::Report, &session);
+        assert!(transaction.errors.is_empty());
+        assert!(transaction.preview());
+        assert!(!fixture.path("a/target.sh").exists());
+        assert!(transaction.commit(&mut session));
+        for target in ["a/target.sh", "b/target.sh"] {
+            let content = std::fs::read_to_string(fixture.path(target)).unwrap();
+            assert!(content.contains("old"));
+        }
+    }
+
+    #[test]
+    fn test_transaction_clash() {
+        let fixture = Fixture::new();
+        let first = transaction_source(&fixture, "first.sh", "a/target.sh", "old");
+        let clash = transaction_source(&fixture, "clash.sh", "a/target.sh", "clash");
+
+        // two sources for one target are refused before anything is written
+        let mut session = fixture.session();
+        let transaction = ApplyTransaction::plan(&[first, clash], ConflictStyle::Report, &session);
+        assert_eq!(transaction.errors.len(), 1);
+        assert!(!transaction.commit(&mut session));
+        assert!(!fixture.path("a/target.sh").exists());
+    }
+
+    #[test]
+    fn test_transaction_rollback() {
+        let fixture = Fixture::new();
+        let sources = [
+            transaction_source(&fixture, "first.sh", "a/target.sh", "old"),
+            transaction_source(&fixture, "second.sh", "b/target.sh", "old"),
+        ];
+        let mut session = fixture.session();
+        assert!(
+            ApplyTransaction::plan(&sources, ConflictStyle::Report, &session).commit(&mut session)
+        );
+        let target = fixture.path("a/target.sh");
+        let before = std::fs::read_to_string(&target).unwrap();
+
+        // a write failing late restores the targets written before it
+        transaction_source(&fixture, "first.sh", "a/target.sh", "new");
+        transaction_source(&fixture, "second.sh", "b/target.sh", "new");
+        let mut session = fixture.session();
+        let transaction = ApplyTransaction::plan(&sources, ConflictStyle::Report, &session);
+        assert!(transaction.errors.is_empty());
+        std::fs::remove_dir_all(fixture.path("b")).unwrap();
+        fixture.write("b", "not a directory");
+        assert!(!transaction.commit(&mut session));
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), before);
+    }
+
+    #[test]
+    fn test_transaction_metafile() {
+        let fixture = Fixture::new();
+        let source = fixture.write("source.json", "{}\n");
+        let mut metafile = MetaFile::read(source.clone());
+        metafile.targetfile = Some(fixture.path("a/target.json").to_string_lossy().to_string());
+        metafile.write_to_file(&mut fixture.session()).unwrap();
+
+        // a new target of a metafile gets the planned content and metafile
+        let mut session = fixture.session();
+        let mut transaction = ApplyTransaction::plan(&[source], ConflictStyle::Report, &session);
+        assert!(transaction.preview());
+        assert!(!fixture.path("a/target.json").exists());
+        assert!(transaction.commit(&mut session));
+        let target = DotFile::from_pathbuf(&fixture.path("a/target.json")).unwrap();
+        assert_eq!(target.to_string(), "{}\n");
+        assert!(!target.modified);
+        let metacontent =
+            std::fs::read_to_string(fixture.path("a/target.json.imosid.toml")).unwrap();
+        assert!(metacontent.contains("source.json"));
+    }
+
+    // a change of the first section of FILE_CONTENT, and a failed write
+    fn journal_entries(target: &Path) -> [JournalEntry; 2] {
+        let changed = FILE_CONTENT.replace("first section", "changed section");
+        let entry = JournalEntry::new(
+            target,
+            Some(FILE_CONTENT.as_bytes()),
+            Some(changed.as_bytes()),
+        )
+        .with_source(Path::new("/dotfiles/test.sh"));
+        let failed = JournalEntry::new(target, None, Some(b"new\n")).failed("disk full");
+        [entry, failed]
+    }
+
+    #[test]
+    fn test_journal() {
+        let fixture = Fixture::new();
+        let mut journal = fixture.session().journal;
+        journal.command = String::from("apply");
+        assert!(journal.entries().unwrap().is_empty());
+
+        let [entry, failed] = journal_entries(&fixture.path("test.sh"));
+        // only the section whose content or markers changed is touched
+        assert_eq!(entry.sections.len(), 1);
+        assert_eq!(entry.sections[0].name, "firstsection");
+        assert!(entry.sections[0].before.is_some());
+        journal.append(&entry).unwrap();
+        journal.append(&failed).unwrap();
+
+        let entries = journal.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].command, "apply");
+        assert_eq!(entries[0].sources, ["/dotfiles/test.sh"]);
+        assert_eq!(entries[0].sections, entry.sections);
+        assert_eq!(entries[1].result, "failed");
+        assert_eq!(entries[1].before, None);
+        assert_eq!(entries[1].after, None);
+        assert_eq!(entries[1].message.as_deref(), Some("disk full"));
+    }
+
+    #[test]
+    fn test_journal_torn_append() {
+        let fixture = Fixture::new();
+        let journal = fixture.session().journal;
+        let [entry, _] = journal_entries(&fixture.path("test.sh"));
+        journal.append(&entry).unwrap();
+
+        // a torn append only loses its own entry
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(fixture.path("state/journal.jsonl"))
+            .unwrap();
+        file.write_all(b"{\"time\":12,\"comm").unwrap();
+        journal.append(&entry).unwrap();
+        assert_eq!(journal.entries().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let target = Fixture::new().path("test.sh");
+        let entries = journal_entries(&target);
+        let count =
+            |filter: LogFilter| entries.iter().filter(|entry| filter.matches(entry)).count();
+        assert_eq!(count(LogFilter::default()), 2);
+        let section = Some(String::from("firstsection"));
+        assert_eq!(
+            count(LogFilter {
+                section,
+                ..Default::default()
+            }),
+            1
+        );
+        let file = Some(PathBuf::from("/dotfiles/test.sh"));
+        assert_eq!(
+            count(LogFilter {
+                file,
+                ..Default::default()
+            }),
+            1
+        );
+        assert_eq!(
+            count(LogFilter {
+                file: Some(target),
+                ..Default::default()
+            }),
+            2
+        );
+        let since = Some(entries[0].time + 1);
+        assert_eq!(
+            count(LogFilter {
+                since,
+                ..Default::default()
+            }),
+            0
+        );
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("1970-01-02", 0), Ok(86400));
+        assert_eq!(parse_since("2024-03-01 12:30", 0), Ok(1709296200));
+        assert_eq!(parse_since("2024-03-01T12:30:15", 0), Ok(1709296215));
+        assert_eq!(parse_since("2d", 3 * 86400), Ok(86400));
+        assert_eq!(parse_since("1w", 0), Ok(0));
+        assert!(parse_since("2024-13-01", 0).is_err());
+        assert!(parse_since("3y", 0).is_err());
+        assert!(parse_since("yesterday", 0).is_err());
+        assert!(parse_since("18446744073709551615w", 0).is_err());
+        assert!(parse_since("9223372036854775807-01-01", 0).is_err());
+    }
+
+    #[test]
+    fn test_journal_sources() {
+        let fixture = Fixture::new();
+        let source = fixture.write(
+            "source.sh",
+            "#... first begin\n#... first hash 0\nnew\n#... first end\n",
+        );
+        let source = source.to_string_lossy().to_string();
+        let targetpath = fixture.write(
+            "target.sh",
+            format!(
+                "#... first begin\n#... first source {}\n#... first hash 0\nold\n#... first end\n",
+                source
+            ),
+        );
+        let mut session = fixture.session();
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        target.compile();
+        target.write_to_file(&mut session).unwrap();
+        let mut target = DotFile::from_pathbuf(&targetpath).unwrap();
+        assert!(target.update(&[], &UpdateOptions::default(), &session));
+        target.write_to_file(&mut session).unwrap();
+
+        // the sources of an update, the file itself for a compile
+        let entries = session.journal.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].sources, [display_path(&targetpath)]);
+        assert!(entries[1].sources.contains(&source));
+    }
+
+    #[test]
+    fn test_journal_metafile() {
+        let fixture = Fixture::new();
+        let path = fixture.write("test.json", "{}\n");
+        let mut session = fixture.session();
+        MetaFile::read(path.clone())
+            .write_to_file(&mut session)
+            .unwrap();
+        let mut dotfile = DotFile::from_pathbuf(&path).unwrap();
+        dotfile.metafile.as_mut().unwrap().content = String::from("{\"a\": 1}\n");
+        dotfile.write_to_file(&mut session).unwrap();
+
+        // the file and its metafile are changes of their own
+        let entries = session.journal.entries().unwrap();
+        let metapath = display_path(&fixture.path("test.json.imosid.toml"));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].target, metapath);
+        assert_eq!(entries[0].sources, [display_path(&path)]);
+        assert_eq!(entries[1].target, display_path(&path));
+        assert_ne!(entries[1].before, entries[1].after);
+        assert_eq!(entries[2].target, metapath);
+        assert!(entries.iter().all(|entry| entry.result == "changed"));
+    }
 }
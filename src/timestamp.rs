@@ -0,0 +1,120 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// all times are utc, in seconds since the unix epoch
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// year, month and day of a day since the epoch
+// see howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+// the inverse of civil_from_days
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn fields(seconds: u64) -> (i64, i64, i64, u64, u64, u64) {
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    (year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+/// yyyymmdd-hhmmss, which sorts in order and can be used in file names
+pub fn format_compact(seconds: u64) -> String {
+    let (year, month, day, hour, minute, second) = fields(seconds);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// yyyy-mm-dd hh:mm:ss
+pub fn format_time(seconds: u64) -> String {
+    let (year, month, day, hour, minute, second) = fields(seconds);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// a point in time given as a date like 2024-05-01, a date and time like
+/// 2024-05-01 12:30 or a duration before now like 30m, 12h, 3d or 2w
+pub fn parse_since(input: &str, now: u64) -> Result<u64, String> {
+    let input = input.trim();
+    let invalid = || {
+        format!(
+            "invalid time {}, use a date like 2024-05-01, a time like 2024-05-01 12:30 or an age like 3d",
+            input
+        )
+    };
+
+    if let Some(unit) = input.chars().last().filter(char::is_ascii_alphabetic) {
+        let count = input[..input.len() - 1]
+            .parse::<u64>()
+            .map_err(|_| invalid())?;
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let age = count.checked_mul(seconds).ok_or_else(invalid)?;
+        return Ok(now.saturating_sub(age));
+    }
+
+    let (date, time) = match input.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (input, None),
+    };
+    let numbers = |text: &str, separator: char| -> Result<Vec<i64>, String> {
+        text.split(separator)
+            .map(|number| number.parse::<i64>().map_err(|_| invalid()))
+            .collect()
+    };
+    let date = numbers(date, '-')?;
+    let [year, month, day] = date[..] else {
+        return Err(invalid());
+    };
+    // four digit years keep the seconds far from overflowing
+    if !(0..=9999).contains(&year) || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    let time = match time {
+        Some(time) => numbers(time, ':')?,
+        None => Vec::new(),
+    };
+    let (hour, minute, second) = match time[..] {
+        [] => (0, 0, 0),
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+    if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second) {
+        return Err(invalid());
+    }
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    u64::try_from(seconds).map_err(|_| invalid())
+}
@@ -0,0 +1,286 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+
+use crate::atomicwrite::write_atomic;
+use crate::diagnostic::Severity;
+use crate::files::{expand_tilde, ApplyPlan, ApplyResult, ConflictStyle, DotFile};
+use crate::journal::{Journal, JournalEntry};
+use crate::session::Session;
+
+/// a file as it was before the transaction wrote it
+struct Original {
+    path: PathBuf,
+    content: Option<Vec<u8>>, // None if the file did not exist
+    mode: Option<u32>,
+}
+
+impl Original {
+    fn read(path: &Path) -> io::Result<Original> {
+        let (content, mode) = match fs::read(path) {
+            Ok(content) => (
+                Some(content),
+                Some(fs::metadata(path)?.permissions().mode() & 0o7777),
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound => (None, None),
+            Err(e) => return Err(e),
+        };
+        Ok(Original {
+            path: path.to_path_buf(),
+            content,
+            mode,
+        })
+    }
+
+    fn is_current(&self) -> bool {
+        match fs::read(&self.path) {
+            Ok(content) => self.content.as_ref() == Some(&content),
+            Err(e) => e.kind() == ErrorKind::NotFound && self.content.is_none(),
+        }
+    }
+
+    fn restore(&self, journal: &Journal) -> io::Result<()> {
+        let mut entry = JournalEntry::new(
+            &self.path,
+            fs::read(&self.path).ok().as_deref(),
+            self.content.as_deref(),
+        )
+        // the content the target had before the transaction
+        .with_source(&self.path);
+        let result = match &self.content {
+            Some(content) => write_atomic(&self.path, content, self.mode),
+            None => fs::remove_file(&self.path),
+        };
+        match &result {
+            Ok(()) => {
+                entry.result = String::from("rolled back");
+                journal.record(&entry);
+            }
+            Err(e) => journal.record(&entry.failed(&e.to_string())),
+        }
+        result
+    }
+}
+
+/// a source and what applying it does to its target
+struct Change {
+    source: DotFile,
+    target: PathBuf,
+    plan: ApplyPlan,
+}
+
+/// apply several files at once, either all targets are written or none
+///
+/// every change is planned and checked before the first file is written.
+/// if a write fails anyway, the targets written so far are restored.
+pub struct ApplyTransaction {
+    changes: Vec<Change>,
+    pub errors: Vec<String>, // problems found while planning, nothing is written if there are any
+}
+
+impl ApplyTransaction {
+    /// plan applying the given files, files without a target are left out
+    pub fn plan(
+        paths: &[PathBuf],
+        conflicts: ConflictStyle,
+        session: &Session,
+    ) -> ApplyTransaction {
+        let mut transaction = ApplyTransaction {
+            changes: Vec::new(),
+            errors: Vec::new(),
+        };
+        for path in paths {
+            let source = match DotFile::from_pathbuf(path) {
+                Ok(source) => source,
+                // binary files cannot contain markers
+                Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+                Err(e) => {
+                    transaction
+                        .errors
+                        .push(format!("could not open {}: {}", path.display(), e));
+                    continue;
+                }
+            };
+            let Some(target) = &source.targetfile else {
+                continue;
+            };
+            let target = PathBuf::from(expand_tilde(target));
+            if source
+                .diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error)
+            {
+                source.print_diagnostics(Severity::Error);
+                transaction
+                    .errors
+                    .push(format!("{} has errors", source.filename));
+                continue;
+            }
+            match source.plan_apply(conflicts, session) {
+                Ok(ApplyPlan::Unchanged) => {}
+                Ok(plan) => transaction.changes.push(Change {
+                    source,
+                    target,
+                    plan,
+                }),
+                Err(e) => transaction.errors.push(e),
+            }
+        }
+        transaction.validate();
+        transaction
+    }
+
+    fn validate(&mut self) {
+        for (index, change) in self.changes.iter().enumerate() {
+            let display = change.target.display();
+            if let Some(other) = self.changes[..index]
+                .iter()
+                .find(|other| other.target == change.target)
+            {
+                self.errors.push(format!(
+                    "{} and {} both apply to {}",
+                    other.source.filename, change.source.filename, display
+                ));
+            }
+            let (ApplyPlan::Create(planned) | ApplyPlan::Update(planned)) = &change.plan else {
+                continue;
+            };
+            // the result has to read back as the file it is meant to be
+            let reparsed =
+                DotFile::from_content(&change.target.to_string_lossy(), &planned.to_string());
+            if reparsed
+                .diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.severity == Severity::Error)
+            {
+                self.errors
+                    .push(format!("applying would break the markers of {}", display));
+            }
+            if let Err(e) = check_writable(&change.target) {
+                self.errors.push(format!("cannot write {}: {}", display, e));
+            }
+        }
+    }
+
+    /// print the planned changes as diffs, true if there are any
+    pub fn preview(&mut self) -> bool {
+        let mut pending = false;
+        for change in self.changes.iter_mut() {
+            if let ApplyPlan::Create(planned) | ApplyPlan::Update(planned) = &mut change.plan {
+                let diff = planned.pending_diff();
+                print!("{}", diff);
+                pending = pending || !diff.is_empty();
+            }
+        }
+        pending
+    }
+
+    /// write every planned change, or restore what was written if one fails
+    /// what happens is reported as it happens, false if nothing was applied
+    pub fn commit(self, session: &mut Session) -> bool {
+        if !self.errors.is_empty() {
+            for error in &self.errors {
+                eprintln!("{} {}", "error:".red().bold(), error);
+            }
+            eprintln!("{}", "nothing was applied".red().bold());
+            return false;
+        }
+        if self.changes.is_empty() {
+            println!("{}", "nothing to do".bold());
+            return true;
+        }
+
+        let count = self.changes.len();
+        let mut originals: Vec<Original> = Vec::new();
+        for change in self.changes {
+            let metapath = PathBuf::from(format!("{}.imosid.toml", change.target.display()));
+            let mut failure = None;
+            for path in [&change.target, &metapath] {
+                if originals.iter().any(|original| &original.path == path) {
+                    continue;
+                }
+                match Original::read(path) {
+                    Ok(original) => originals.push(original),
+                    Err(e) => failure = Some(format!("could not read {}: {}", path.display(), e)),
+                }
+            }
+            if failure.is_none() {
+                if let ApplyResult::Error = change.source.commit_apply(change.plan, session) {
+                    failure = Some(format!(
+                        "could not apply {} to {}",
+                        change.source.filename,
+                        change.target.display()
+                    ));
+                }
+            }
+            if let Some(failure) = failure {
+                eprintln!("{} {}", "error:".red().bold(), failure);
+                rollback(&originals, &session.journal);
+                return false;
+            }
+        }
+        println!(
+            "{}",
+            format!("all targets applied ({})", count).green().bold()
+        );
+        true
+    }
+}
+
+// put back every file the transaction changed, newest first
+fn rollback(originals: &[Original], journal: &Journal) {
+    let mut failed = false;
+    for original in originals.iter().rev() {
+        if original.is_current() {
+            continue;
+        }
+        match original.restore(journal) {
+            Ok(()) => println!("restored {}", original.path.display().to_string().bold()),
+            Err(e) => {
+                eprintln!(
+                    "{} {}: {}",
+                    "could not restore".red().bold(),
+                    original.path.display(),
+                    e
+                );
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        eprintln!(
+            "{}",
+            "some files could not be restored, see imosid backups"
+                .red()
+                .bold()
+        );
+    } else {
+        eprintln!("{}", "rolled back, nothing was applied".red().bold());
+    }
+}
+
+// files are replaced by renaming a new file into their directory,
+// which may not exist yet
+fn check_writable(path: &Path) -> io::Result<()> {
+    let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    let mut dir = path.parent();
+    while let Some(candidate) = dir {
+        if candidate.is_dir() {
+            break;
+        }
+        dir = candidate.parent();
+    }
+    let dir = match dir {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".imosid-probe-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    fs::remove_file(&probe)
+}
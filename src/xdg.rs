@@ -0,0 +1,28 @@
+use std::env;
+use std::path::PathBuf;
+
+// base directories as described by the xdg base directory specification
+fn base_dir(variable: &str, fallback: &str) -> PathBuf {
+    match env::var_os(variable) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()
+            .expect("could not find home directory")
+            .join(fallback),
+    }
+}
+
+pub fn config_home() -> PathBuf {
+    base_dir("XDG_CONFIG_HOME", ".config")
+}
+
+pub fn cache_home() -> PathBuf {
+    base_dir("XDG_CACHE_HOME", ".cache")
+}
+
+pub fn data_home() -> PathBuf {
+    base_dir("XDG_DATA_HOME", ".local/share")
+}
+
+pub fn state_home() -> PathBuf {
+    base_dir("XDG_STATE_HOME", ".local/state")
+}